@@ -1,87 +1,756 @@
 use std::{
+    collections::HashMap,
     error::Error,
-    fs::File,
+    fs::{create_dir_all, File},
     io::BufWriter,
     net::{SocketAddr, ToSocketAddrs},
-    path::Path,
-    time::Instant,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
-    common::{read_all_from_channel, send_bytes_on_channel},
-    recovery_metrics_logger::RecoveryMetricsLogger,
+    common::{
+        read_all_from_channel, read_header, send_bytes_on_channel, ReceiveStats, TransferCounter,
+        APPLICATION_PROTOCOL, ECHO_RESPONSE_SIZE,
+    },
+    impairment::{start_relay, ImpairmentConfig},
+    interval_reporter::IntervalReporter,
+    metrics_writer::AsyncMetricsWriter,
+    qlog::QlogLogger,
+    record_writer::{MetricsField, RecordEncoding, RecordFormat},
+    recovery_metrics_logger::{
+        create_logfile, init_process_start, log_compression, RecoveryMetricsLogger, TimestampMode,
+        CONN_ID_PLACEHOLDER,
+    },
+    report::{RequestLogger, RequestRecord, Summary, SummaryFormat},
+    runtime::Runtime,
+    workload::{ArrivalProcess, SizeDistribution, ThinkTime},
 };
+use bytes::Bytes;
 use bytesize::ByteSize;
-use clap::Parser;
-use log::{error, info};
-use s2n_quic::{client::Connect, Client};
-use tokio::{self, signal};
+use clap::{ArgEnum, Parser};
+use futures_util::{future::join_all, stream::FuturesUnordered, StreamExt};
+use log::{debug, error, info, warn};
+use rand::{rngs::StdRng, SeedableRng};
+use s2n_quic::{
+    client::Connect,
+    connection::{Handle, StreamAcceptor},
+    provider::{io, tls::s2n_tls::certificate::IntoCertificate},
+    stream::{ReceiveStream, SendStream},
+    Client,
+};
+use tokio::{
+    self, signal,
+    sync::{oneshot, watch},
+};
+
+#[cfg(feature = "simulation")]
+use crate::simulation::{start_server, SimulatedRandom, SimulationConfig, SERVER_NAME};
+#[cfg(feature = "simulation")]
+use s2n_quic::provider::random;
+#[cfg(feature = "simulation")]
+use s2n_quic_core::crypto::tls::testing::certificates;
 
 mod common;
+mod impairment;
+mod interval_reporter;
+mod metrics_writer;
+#[cfg(feature = "simulation")]
+mod perf_server;
+mod qlog;
+mod record_writer;
 mod recovery_metrics_logger;
+mod report;
+mod runtime;
+#[cfg(feature = "simulation")]
+mod simulation;
+mod workload;
+
+/// How long to wait for connections to close at the end of the run before the logfiles are flushed
+const LOG_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Lowest accepted `--rate` in requests per second, slower schedules would leave hours between
+/// two requests
+const MIN_RATE: f64 = 0.001;
 
 /// Perf client used to investigate s2n-quic CC observations
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 struct Args {
-    /// CC Logfile
+    /// CC Logfile, `{conn_id}` in the path writes one file per connection
     #[clap(short, long)]
     cc_logfile: Option<String>,
-    #[clap(short, long)]
-    remote: String,
+    /// Logfile for packet sent/lost, ACK, congestion and PTO events
+    #[clap(long, requires = "cc-logfile")]
+    cc_event_logfile: Option<String>,
+    /// Clock used for the `time` column of the CC logs
+    #[clap(long, arg_enum, default_value = "unix")]
+    cc_time: TimestampMode,
+    /// Comma-separated columns of the CC log, e.g. `time,min_rtt,congestion_window`, defaults to
+    /// all columns
+    #[clap(long, use_value_delimiter = true)]
+    cc_fields: Vec<MetricsField>,
+    /// Encoding of the CC log
+    #[clap(long, arg_enum, default_value = "csv")]
+    cc_format: RecordEncoding,
+    /// Write at most one CC log row per connection and interval (e.g. `10ms`)
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    cc_sample_interval: Option<Duration>,
+    /// Only write a CC log row if `congestion_window` or `bytes_in_flight` changed by more than
+    /// this many bytes
+    #[clap(long)]
+    cc_change_threshold: Option<u32>,
+    /// Directory to write a qlog (JSON-SEQ) file per connection into
     #[clap(long)]
-    request_size: String,
+    qlog: Option<String>,
+    /// Server address, not used with `--simulate`
+    #[clap(short, long)]
+    #[cfg_attr(feature = "simulation", clap(required_unless_present = "simulate"))]
+    #[cfg_attr(not(feature = "simulation"), clap(required = true))]
+    remote: Option<String>,
+    /// Traffic pattern of every request stream, `sequential` sends the request before reading the
+    /// response like the client did before `--mode` existed
+    #[clap(long, arg_enum, default_value = "sequential")]
+    mode: TrafficMode,
+    /// Stream type used for requests, with `unidirectional` the server responds on a stream it
+    /// opens itself
+    #[clap(long, arg_enum, default_value = "bidirectional")]
+    stream_type: StreamType,
+    /// Bytes uploaded per request, excluding the 8-byte request header, which older versions
+    /// counted towards the request size (not used by `download`).
+    /// Either a size or a distribution: `uniform:<min>-<max>`, `exp:<mean>`,
+    /// `lognormal:<median>,<sigma>` or `empirical:<file>`
+    #[clap(long, default_value = "0", parse(try_from_str = SizeDistribution::parse))]
+    request_size: SizeDistribution,
+    /// Bytes downloaded per request (not used by `upload` and `echo`), same syntax as
+    /// `--request-size`
+    #[clap(long, default_value = "0", parse(try_from_str = SizeDistribution::parse))]
+    response_size: SizeDistribution,
+    /// Pause between a response and the next request of a stream, `<duration>` or
+    /// `exp:<mean duration>`
+    #[clap(long, parse(try_from_str = ThinkTime::parse))]
+    think_time: Option<ThinkTime>,
+    /// Seed for request sizes and think times, every request stream draws from its own generator
+    #[clap(long, default_value = "0")]
+    seed: u64,
+    /// Issue this many requests per second across all connections on an open-loop schedule
+    /// instead of waiting for responses, `--streams` is ignored
     #[clap(long)]
-    response_size: String,
+    rate: Option<f64>,
+    /// Schedule of the `--rate` requests
+    #[clap(long, arg_enum, default_value = "fixed")]
+    arrival: ArrivalProcess,
+    /// Certificate the server is verified with, `--simulate` uses the s2n-quic test certificate
     #[clap(long)]
-    cert_file: String,
+    #[cfg_attr(feature = "simulation", clap(required_unless_present = "simulate"))]
+    #[cfg_attr(not(feature = "simulation"), clap(required = true))]
+    cert_file: Option<String>,
     #[clap(long)]
     disable_gso: bool,
+    /// Number of concurrent request streams per connection
+    #[clap(long, default_value = "1")]
+    streams: usize,
+    /// Number of concurrent connections
+    #[clap(long, default_value = "1")]
+    connections: usize,
+    /// Open each connection from its own endpoint/socket instead of sharing one
+    #[clap(long)]
+    separate_endpoints: bool,
+    /// Stop the test after this duration (e.g. `30s`, `5m`)
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    duration: Option<Duration>,
+    /// Stop the test after this many requests across all connections and streams
+    #[clap(long)]
+    requests: Option<u64>,
+    /// Write an end-of-run summary report to this file
+    #[clap(long)]
+    summary_file: Option<String>,
+    #[clap(long, arg_enum, default_value = "json")]
+    summary_format: SummaryFormat,
+    /// Significant figures of the latency histogram, between 0 and 5
+    #[clap(long, default_value = "3")]
+    latency_precision: u8,
+    /// Write the latency histogram in the HdrHistogram interval log format to this file
+    #[clap(long)]
+    latency_histogram_file: Option<String>,
+    /// Write a CSV row with timings for every completed request to this file
+    #[clap(long)]
+    request_logfile: Option<String>,
+    /// Report the throughput per stream and connection every this many seconds
+    #[clap(long)]
+    interval: Option<f64>,
+    /// Impairments emulated by an in-process relay in front of the remote
+    #[clap(flatten)]
+    impairment: ImpairmentConfig,
+    #[cfg(feature = "simulation")]
+    #[clap(flatten)]
+    simulation: SimulationConfig,
+}
+
+impl Args {
+    /// Whether the client runs against an in-process server in simulated time (`--simulate`)
+    fn simulate(&self) -> bool {
+        #[cfg(feature = "simulation")]
+        return self.simulation.simulate;
+        #[cfg(not(feature = "simulation"))]
+        return false;
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TrafficMode {
+    /// Send the request, then receive the response
+    Sequential,
+    /// Send the request, the server does not respond
+    Upload,
+    /// Only request a response of `--response-size`
+    Download,
+    /// Send the request and receive the response at the same time
+    Bidirectional,
+    /// Send the request and receive it back from the server at the same time
+    Echo,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamType {
+    Bidirectional,
+    Unidirectional,
+}
+
+/// Server-initiated response streams of the unidirectional stream type, handed to the request
+/// waiting for them by the id of the request stream they answer
+#[derive(Default)]
+struct ResponseStreams {
+    pending: Mutex<HashMap<u64, oneshot::Sender<ResponseStream>>>,
+}
+
+struct ResponseStream {
+    recv: ReceiveStream,
+    /// Response data that arrived together with the request stream id
+    prefix: Bytes,
+    received_at: Instant,
+}
+
+/// Response stream a request waits for, unregisters the request stream when dropped so requests
+/// that quit or failed don't leak their entry
+struct PendingResponse {
+    response_streams: Arc<ResponseStreams>,
+    request_stream_id: u64,
+    receiver: oneshot::Receiver<ResponseStream>,
+}
+
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        self.response_streams
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.request_stream_id);
+    }
+}
+
+impl ResponseStreams {
+    /// Registers a request stream, the pending response resolves once the server opened its
+    /// response stream
+    fn register(self: &Arc<Self>, request_stream_id: u64) -> PendingResponse {
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(request_stream_id, sender);
+        return PendingResponse {
+            response_streams: self.clone(),
+            request_stream_id,
+            receiver,
+        };
+    }
+
+    fn dispatch(&self, request_stream_id: u64, response_stream: ResponseStream) {
+        match self.pending.lock().unwrap().remove(&request_stream_id) {
+            Some(sender) => {
+                let _ = sender.send(response_stream);
+            }
+            None => warn!(
+                "Received a response stream for unknown request stream {}.",
+                request_stream_id
+            ),
+        }
+    }
+}
+
+/// Where the response of a request arrives
+enum ResponseSource {
+    Stream(ReceiveStream),
+    Pushed(PendingResponse),
+    /// Unidirectional request without a response
+    Nothing,
+}
+
+/// State shared by all request loops of all connections
+struct RequestLoopContext {
+    runtime: Runtime,
+    server_name: &'static str,
+    mode: TrafficMode,
+    stream_type: StreamType,
+    request_size: SizeDistribution,
+    response_size: SizeDistribution,
+    think_time: Option<ThinkTime>,
+    seed: u64,
+    streams: usize,
+    /// Open-loop request rate per connection
+    rate: Option<f64>,
+    arrival: ArrivalProcess,
+    outstanding_requests: AtomicU64,
+    max_outstanding_requests: AtomicU64,
+    request_limit: RequestLimit,
+    request_logger: Option<RequestLogger>,
+    interval_reporter: Option<Arc<IntervalReporter>>,
+}
+
+/// Shared request budget across all request loops, sends exit once the last request completed
+struct RequestLimit {
+    limit: Option<u64>,
+    started: AtomicU64,
+    completed: AtomicU64,
+    exit_sender: Arc<watch::Sender<bool>>,
+}
+
+impl RequestLimit {
+    fn new(limit: Option<u64>, exit_sender: Arc<watch::Sender<bool>>) -> Self {
+        Self {
+            limit,
+            started: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            exit_sender,
+        }
+    }
+
+    /// Claims a request from the budget, returns `false` if the budget is exhausted
+    fn try_start(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.started.fetch_add(1, Ordering::Relaxed) < limit,
+            None => true,
+        }
+    }
+
+    fn complete(&self) {
+        let completed = self.completed.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if Some(completed) == self.limit {
+            info!("Completed {} requests, sending exit.", completed);
+            let _ = self.exit_sender.send(true);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    init_process_start();
     env_logger::init();
     let args = Args::parse();
 
-    let amount_to_request: u64 = match args.response_size.parse::<ByteSize>() {
-        Ok(parsed) => {
-            if parsed.as_u64() > u64::MAX {
-                let msg = format!(
-                    "Can't request responses greater than than {}",
-                    ByteSize(u64::MAX)
-                );
-                return Err(msg.into());
-            } else {
-                parsed.as_u64()
-            }
-        }
-        Err(e) => {
-            let msg = format!("Failed to parse response size, error: {}", e);
-            return Err(msg.into());
+    // only keep the sizes the mode transfers, so logs and reports show what is actually sent
+    let (request_size, response_size) = match args.mode {
+        TrafficMode::Upload => (args.request_size.clone(), SizeDistribution::Fixed(0)),
+        TrafficMode::Download => (SizeDistribution::Fixed(0), args.response_size.clone()),
+        TrafficMode::Sequential | TrafficMode::Bidirectional => {
+            (args.request_size.clone(), args.response_size.clone())
         }
+        // the response mirrors the request
+        TrafficMode::Echo => (args.request_size.clone(), args.request_size.clone()),
     };
-    let amount_to_send: u64 = match args.request_size.parse::<ByteSize>() {
-        Ok(parsed) => {
-            if parsed.as_u64() < 8 {
-                let msg = format!("Can't send less than 8 bytes application data per request due to protocol header size!");
-                return Err(msg.into());
-            } else {
-                parsed.as_u64() - 8
+
+    if args.mode != TrafficMode::Echo && response_size.max() == Some(ECHO_RESPONSE_SIZE) {
+        return Err(format!(
+            "The response size {} is reserved for echo requests!",
+            ECHO_RESPONSE_SIZE
+        )
+        .into());
+    }
+
+    if let Some(rate) = args.rate {
+        // an infinite rate would schedule requests without ever waiting
+        if !rate.is_finite() || rate < MIN_RATE {
+            return Err(format!(
+                "The request rate has to be a finite number of at least {} requests per second!",
+                MIN_RATE
+            )
+            .into());
+        }
+    }
+
+    if let Some(impair_rate) = args.impairment.impair_rate {
+        if !impair_rate.is_finite() || impair_rate <= 0f64 {
+            return Err("The impairment rate has to be a positive number!".into());
+        }
+    }
+
+    let impair_percentages = [
+        ("--impair-loss", args.impairment.impair_loss),
+        ("--impair-burst-loss", args.impairment.impair_burst_loss),
+        ("--impair-reorder", args.impairment.impair_reorder),
+        ("--impair-duplicate", args.impairment.impair_duplicate),
+    ];
+    for (option, percentage) in impair_percentages {
+        if let Some(percentage) = percentage {
+            // also rejects NaN
+            if !(0f64..=100f64).contains(&percentage) {
+                return Err(format!("{} has to be a percentage between 0 and 100!", option).into());
             }
         }
-        Err(e) => {
-            let msg = format!("Failed to parse request size, error: {}", e);
-            return Err(msg.into());
+    }
+
+    if let Some(interval) = args.interval {
+        if !interval.is_finite() || interval <= 0f64 {
+            return Err("The report interval has to be a positive number of seconds!".into());
+        }
+    }
+
+    if args.latency_precision > 5 {
+        return Err("The latency histogram precision can't exceed 5 significant figures!".into());
+    }
+
+    if args.streams == 0 {
+        return Err("At least one request stream is required!".into());
+    }
+
+    if args.connections == 0 {
+        return Err("At least one connection is required!".into());
+    }
+
+    if args.simulate() {
+        if args.requests.is_none() && args.duration.is_none() {
+            return Err("A simulation needs --requests or --duration to end!".into());
+        }
+
+        if args.impairment.is_enabled() {
+            return Err("The impairment relay can't be used in a simulation, use the --sim-* options instead!".into());
+        }
+
+        if args.cc_logfile.is_some()
+            && matches!(args.cc_time, TimestampMode::Unix | TimestampMode::Monotonic)
+        {
+            warn!("The CC log's time column follows the wall clock, use --cc-time event or connection for reproducible logs.");
+        }
+    }
+
+    // simulated time doesn't advance while the writer queue is full, so a simulation waits for the
+    // writer instead of dropping records
+    let (log_writer, log_writer_thread) = AsyncMetricsWriter::spawn(args.simulate());
+
+    let (exit_sender, quitting_receiver) = watch::channel::<bool>(false);
+    let exit_sender = Arc::new(exit_sender);
+
+    let ctrl_c_exit_sender = exit_sender.clone();
+    tokio::spawn(async move {
+        let _ = signal::ctrl_c().await;
+        info!("Received ctrl-c, sending exit.");
+        let _ = ctrl_c_exit_sender.send(true);
+    });
+
+    let request_logger = match args.request_logfile {
+        Some(ref logfile_path) => {
+            let file = File::create(logfile_path)?;
+            Some(RequestLogger::new(Box::new(BufWriter::new(file))))
         }
+        None => None,
     };
 
-    let tls = s2n_quic::provider::tls::s2n_tls::Client::builder()
-        .with_certificate(Path::new(&args.cert_file))?
-        .with_application_protocols(vec!["perf"])?
-        .build()?;
+    let interval_reporter = args.interval.map(|interval| {
+        let interval_reporter = Arc::new(IntervalReporter::default());
+        let reporter = interval_reporter.clone();
+        let quitting_receiver = quitting_receiver.clone();
+        tokio::spawn(async move {
+            reporter
+                .run(Duration::from_secs_f64(interval), quitting_receiver)
+                .await
+        });
+        interval_reporter
+    });
+
+    #[cfg(feature = "simulation")]
+    let (runtime, server_name) = match args.simulation.simulate {
+        true => (
+            Runtime::Simulated {
+                origin: Instant::now(),
+            },
+            SERVER_NAME,
+        ),
+        false => (Runtime::Tokio, "echo.test"),
+    };
+    #[cfg(not(feature = "simulation"))]
+    let (runtime, server_name) = (Runtime::Tokio, "echo.test");
+
+    let context = Arc::new(RequestLoopContext {
+        runtime,
+        server_name,
+        mode: args.mode,
+        stream_type: args.stream_type,
+        request_size,
+        response_size,
+        think_time: args.think_time,
+        seed: args.seed,
+        streams: args.streams,
+        rate: args.rate.map(|rate| rate / args.connections as f64),
+        arrival: args.arrival,
+        outstanding_requests: AtomicU64::new(0),
+        max_outstanding_requests: AtomicU64::new(0),
+        request_limit: RequestLimit::new(args.requests, exit_sender.clone()),
+        request_logger,
+        interval_reporter,
+    });
+
+    info!(
+        "Perf-Client started (mode: {:?}, stream type: {:?}, send size: {}, response size: {}, connections: {}, streams: {}).",
+        args.mode,
+        args.stream_type,
+        context.request_size,
+        context.response_size,
+        args.connections,
+        args.streams,
+    );
+
+    #[cfg(feature = "simulation")]
+    let records = match args.simulation.simulate {
+        // the simulation runs to completion on this thread
+        true => tokio::task::block_in_place(|| {
+            run_simulation(
+                &args,
+                &log_writer,
+                context.clone(),
+                exit_sender,
+                quitting_receiver,
+            )
+        })?,
+        false => {
+            run_remote(
+                &args,
+                &log_writer,
+                context.clone(),
+                exit_sender,
+                quitting_receiver,
+            )
+            .await?
+        }
+    };
+    #[cfg(not(feature = "simulation"))]
+    let records = run_remote(
+        &args,
+        &log_writer,
+        context.clone(),
+        exit_sender,
+        quitting_receiver,
+    )
+    .await?;
+    log_writer_thread.shutdown();
+
+    let summary = Summary::new(records, args.latency_precision)?;
+
+    info!(
+        "Completed {} requests (mean send {}it/s, mean rcv {}it/s).",
+        summary.request_count,
+        ByteSize(summary.send_throughput_bps.mean as u64).to_string_as(false),
+        ByteSize(summary.receive_throughput_bps.mean as u64).to_string_as(false),
+    );
+    info!(
+        "Latency including queueing: median {:?}, p95 {:?}, max {:?}.",
+        Duration::from_nanos(summary.latency_ns.median as u64),
+        Duration::from_nanos(summary.latency_ns.p95 as u64),
+        Duration::from_nanos(summary.latency_ns.max as u64),
+    );
+    info!(
+        "Latency percentiles:\n{}",
+        summary.latency_percentile_table()
+    );
+    if args.rate.is_some() {
+        info!(
+            "At most {} requests were outstanding at the same time.",
+            context.max_outstanding_requests.load(Ordering::Relaxed)
+        );
+    }
+
+    if let Some(summary_file) = args.summary_file {
+        summary.write_to_file(&summary_file, args.summary_format)?;
+        info!("Wrote summary to {}.", summary_file);
+    }
+
+    if let Some(latency_histogram_file) = args.latency_histogram_file {
+        summary.write_latency_histogram(&latency_histogram_file)?;
+        info!("Wrote latency histogram to {}.", latency_histogram_file);
+    }
+
+    return Ok(());
+}
+
+/// Runs the connections against `--remote` in wall-clock time, returns the records of their
+/// requests
+async fn run_remote(
+    args: &Args,
+    log_writer: &AsyncMetricsWriter,
+    context: Arc<RequestLoopContext>,
+    exit_sender: Arc<watch::Sender<bool>>,
+    quitting_receiver: watch::Receiver<bool>,
+) -> Result<Vec<RequestRecord>, Box<dyn Error>> {
+    let cert_file = args.cert_file.as_ref().unwrap();
+    let clients = start_clients(args, |endpoint_index| {
+        start_client(
+            args,
+            endpoint_index,
+            log_writer,
+            tokio_io(args)?,
+            #[cfg(feature = "simulation")]
+            random::Default::default(),
+            Path::new(cert_file),
+        )
+    })?;
+
+    let mut addr: SocketAddr = args.remote.as_ref().unwrap().parse()?;
+    if args.impairment.is_enabled() {
+        addr = start_relay(addr, args.impairment.clone()).await?;
+    }
+
+    if let Some(duration) = args.duration {
+        tokio::spawn(stop_after(Runtime::Tokio, duration, exit_sender));
+    }
+
+    let records =
+        run_connections(&clients, addr, args.connections, context, quitting_receiver).await;
+    wait_idle(clients, Runtime::Tokio).await;
+
+    return Ok(records);
+}
+
+/// Runs the connections against a perf server in this process over the simulated network of
+/// `args.simulation`, returns the records of their requests
+#[cfg(feature = "simulation")]
+fn run_simulation(
+    args: &Args,
+    log_writer: &AsyncMetricsWriter,
+    context: Arc<RequestLoopContext>,
+    exit_sender: Arc<watch::Sender<bool>>,
+    quitting_receiver: watch::Receiver<bool>,
+) -> Result<Vec<RequestRecord>, Box<dyn Error>> {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let simulation_records = records.clone();
+
+    let network = args.simulation.network();
+    let simulated_time = io::testing::test_seed(network, args.simulation.sim_seed, |handle| {
+        let addr = start_server(handle, quitting_receiver.clone())?;
+        let clients = start_clients(args, |endpoint_index| {
+            start_client(
+                args,
+                endpoint_index,
+                log_writer,
+                handle.builder().build()?,
+                SimulatedRandom,
+                certificates::CERT_PEM,
+            )
+        })?;
+
+        let connections = args.connections;
+        let duration = args.duration;
+        io::testing::primary::spawn(async move {
+            if let Some(duration) = duration {
+                drop(
+                    context
+                        .runtime
+                        .spawn(stop_after(context.runtime, duration, exit_sender)),
+                );
+            }
+
+            let runtime = context.runtime;
+            let records =
+                run_connections(&clients, addr, connections, context, quitting_receiver).await;
+            wait_idle(clients, runtime).await;
+
+            simulation_records.lock().unwrap().extend(records);
+        });
+
+        return Ok(());
+    })?;
+
+    info!("Simulated {:?}.", simulated_time);
+
+    let records = std::mem::take(&mut *records.lock().unwrap());
+    return Ok(records);
+}
+
+/// Runs `connections` connections to `addr`, spread over the client endpoints, returns the records
+/// of their requests
+async fn run_connections(
+    clients: &[Client],
+    addr: SocketAddr,
+    connections: usize,
+    context: Arc<RequestLoopContext>,
+    quitting_receiver: watch::Receiver<bool>,
+) -> Vec<RequestRecord> {
+    let connections = (0..connections).map(|connection_index| {
+        let client = clients[connection_index % clients.len()].clone();
+        context.runtime.spawn(run_connection(
+            client,
+            addr,
+            connection_index,
+            context.clone(),
+            quitting_receiver.clone(),
+        ))
+    });
+
+    return join_all(connections)
+        .await
+        .into_iter()
+        .filter_map(Result::ok)
+        .flatten()
+        .collect();
+}
+
+/// Sends exit once `duration` elapsed
+async fn stop_after(runtime: Runtime, duration: Duration, exit_sender: Arc<watch::Sender<bool>>) {
+    runtime.sleep_until(runtime.now() + duration).await;
+    info!("Test duration of {:?} elapsed, sending exit.", duration);
+    let _ = exit_sender.send(true);
+}
+
+/// Waits briefly for the closing connections, so their last events are logged before the logfiles
+/// are flushed
+async fn wait_idle(clients: Vec<Client>, runtime: Runtime) {
+    // an endpoint whose connections closed before the wait started isn't woken up to notice it,
+    // waiting for it only ends at the deadline
+    let deadline = runtime.now() + LOG_SHUTDOWN_TIMEOUT;
 
-    let mut io_builder = s2n_quic::provider::io::tokio::Provider::builder();
+    for mut client in clients {
+        tokio::select! {
+            biased;
+
+            _ = client.wait_idle() => {},
+            _ = runtime.sleep_until(deadline) => {
+                debug!("Connections did not close within {:?}.", LOG_SHUTDOWN_TIMEOUT);
+                break;
+            }
+        }
+    }
+}
+
+/// Starts the client endpoints with `start_client`, one per connection with `--separate-endpoints`
+fn start_clients(
+    args: &Args,
+    mut start_client: impl FnMut(Option<usize>) -> Result<Client, Box<dyn Error>>,
+) -> Result<Vec<Client>, Box<dyn Error>> {
+    if args.separate_endpoints {
+        return (0..args.connections)
+            .map(|endpoint_index| start_client(Some(endpoint_index)))
+            .collect();
+    }
+
+    return Ok(vec![start_client(None)?]);
+}
+
+/// Socket of a client endpoint on the network
+fn tokio_io(args: &Args) -> Result<io::tokio::Provider, Box<dyn Error>> {
+    let mut io_builder = io::tokio::Provider::builder();
 
     if args.disable_gso {
         info!("Disabling GSO");
@@ -92,99 +761,708 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_receive_address("0.0.0.0:0".to_socket_addrs()?.next().unwrap())?
         .build()?;
 
-    let client = match args.cc_logfile {
-        Some(logfile_path) => {
-            let file = File::create(logfile_path).unwrap();
-            let logger = RecoveryMetricsLogger::new(Box::new(BufWriter::new(file)));
-            Client::builder()
-                .with_tls(tls)?
-                .with_io(io)?
-                .with_event(logger)?
-                .start()?
+    return Ok(io);
+}
+
+/// Starts a client endpoint that trusts `certificate`, `endpoint_index` is set if every
+/// connection uses its own endpoint
+fn start_client(
+    args: &Args,
+    endpoint_index: Option<usize>,
+    log_writer: &AsyncMetricsWriter,
+    io: impl io::Provider,
+    #[cfg(feature = "simulation")] random: impl random::Provider,
+    certificate: impl IntoCertificate,
+) -> Result<Client, Box<dyn Error>> {
+    let logfile_path = |path: &String| match endpoint_index {
+        Some(endpoint_index) => endpoint_logfile_path(path, endpoint_index),
+        None => path.clone(),
+    };
+
+    let tls = s2n_quic::provider::tls::s2n_tls::Client::builder()
+        .with_certificate(certificate)?
+        .with_application_protocols(vec![APPLICATION_PROTOCOL])?
+        .build()?;
+
+    let metrics_logger = match &args.cc_logfile {
+        Some(cc_logfile) => {
+            let record_format = RecordFormat::new(args.cc_format, args.cc_fields.clone());
+            let mut logger = if cc_logfile.contains(CONN_ID_PLACEHOLDER) {
+                RecoveryMetricsLogger::per_connection(
+                    log_writer.clone(),
+                    logfile_path(cc_logfile),
+                    record_format,
+                )
+            } else {
+                let path = logfile_path(cc_logfile);
+                let logfile = create_logfile(&path)?;
+                RecoveryMetricsLogger::new(log_writer.clone(), &path, logfile, record_format)
+            };
+            if let Some(cc_event_logfile) = &args.cc_event_logfile {
+                let path = logfile_path(cc_event_logfile);
+                let logfile = create_logfile(&path)?;
+                logger = logger.with_event_logfile(&path, logfile);
+            }
+            if let Some(cc_sample_interval) = args.cc_sample_interval {
+                logger = logger.with_sample_interval(cc_sample_interval);
+            }
+            if let Some(cc_change_threshold) = args.cc_change_threshold {
+                logger = logger.with_change_threshold(cc_change_threshold);
+            }
+            Some(logger.with_timestamp_mode(args.cc_time))
         }
+        None => None,
+    };
 
-        None => Client::builder().with_tls(tls)?.with_io(io)?.start()?,
+    let qlog_logger = match &args.qlog {
+        Some(qlog_dir) => Some(QlogLogger::new(
+            qlog_endpoint_dir(qlog_dir, endpoint_index)?,
+            log_writer.clone(),
+        )),
+        None => None,
     };
 
-    let addr: SocketAddr = args.remote.parse()?;
-    let connect = Connect::new(addr).with_server_name("echo.test");
+    let builder = Client::builder().with_tls(tls)?.with_io(io)?;
+    #[cfg(feature = "simulation")]
+    let builder = builder.with_random(random)?;
 
-    let (exit_sender, mut quitting_receiver) = tokio::sync::watch::channel::<bool>(false);
+    let client = match (metrics_logger, qlog_logger) {
+        (Some(metrics_logger), Some(qlog_logger)) => {
+            builder.with_event((metrics_logger, qlog_logger))?.start()?
+        }
+        (Some(metrics_logger), None) => builder.with_event(metrics_logger)?.start()?,
+        (None, Some(qlog_logger)) => builder.with_event(qlog_logger)?.start()?,
+        (None, None) => builder.start()?,
+    };
 
-    tokio::spawn(async move {
-        let _ = signal::ctrl_c().await;
-        info!("Received ctrl-c, sending exit.");
-        let _ = exit_sender.send(true);
-    });
+    return Ok(client);
+}
 
-    info!(
-        "Perf-Client started (send size: {}, response size: {}).",
-        ByteSize(amount_to_send).to_string_as(true),
-        ByteSize(amount_to_request).to_string_as(true),
-    );
+/// Creates the qlog directory, with separate endpoints each one gets its own subdirectory since the
+/// connection ids are only unique per endpoint
+fn qlog_endpoint_dir(
+    qlog_dir: &str,
+    endpoint_index: Option<usize>,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let mut qlog_dir = PathBuf::from(qlog_dir);
+    if let Some(endpoint_index) = endpoint_index {
+        qlog_dir.push(endpoint_index.to_string());
+    }
+
+    create_dir_all(&qlog_dir)?;
+
+    return Ok(qlog_dir);
+}
+
+/// Inserts the endpoint index before the extension of `path`, e.g. `cc.csv` -> `cc.1.csv`
+fn endpoint_logfile_path(path: &str, endpoint_index: usize) -> String {
+    let path = Path::new(path);
+
+    // keep a compression extension at the end, e.g. `cc.csv.gz` becomes `cc.0.csv.gz`
+    let (uncompressed, compression_extension) = match path.extension() {
+        Some(extension) if log_compression(path).is_some() => (
+            path.with_extension(""),
+            format!(".{}", extension.to_string_lossy()),
+        ),
+        _ => (path.to_path_buf(), String::new()),
+    };
+
+    let stem = uncompressed
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let file_name = match uncompressed.extension() {
+        Some(extension) => format!(
+            "{}.{}.{}{}",
+            stem,
+            endpoint_index,
+            extension.to_string_lossy(),
+            compression_extension
+        ),
+        None => format!("{}.{}{}", stem, endpoint_index, compression_extension),
+    };
+
+    return path
+        .with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned();
+}
+
+async fn run_connection(
+    client: Client,
+    addr: SocketAddr,
+    connection_index: usize,
+    context: Arc<RequestLoopContext>,
+    mut quitting_receiver: watch::Receiver<bool>,
+) -> Vec<RequestRecord> {
+    let connect = Connect::new(addr).with_server_name(context.server_name);
+
+    // every `select!` of the request loops polls in order, which keeps simulations reproducible
     tokio::select! {
-        Ok(mut connection) = client.connect(connect) => {
-            info!("Connected.");
-            'request_loop: loop {
-                tokio::select! {
-                    open_res = connection.open_bidirectional_stream() => {
-                        if *quitting_receiver.borrow() {
-                            break;
-                        }
+        biased;
 
-                        let (mut recv, mut send) = open_res.unwrap().split();
+        connect_res = client.connect(connect) => {
+            let connection = match connect_res {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Failed to connect: {}", e);
+                    return Vec::new();
+                }
+            };
 
-                        let send_start = Instant::now();
+            info!("Connected (connection {}).", connection.id());
+            let connection_counter = context
+                .interval_reporter
+                .as_ref()
+                .map(|reporter| reporter.register(format!("conn {}", connection.id()), None));
+            let (handle, acceptor) = connection.split();
 
-                        send.send(amount_to_request.to_be_bytes().to_vec().into()).await.unwrap();
+            let response_streams = Arc::new(ResponseStreams::default());
+            let _acceptor = match context.stream_type {
+                StreamType::Unidirectional => {
+                    drop(context.runtime.spawn(accept_response_streams(acceptor, response_streams.clone(), context.runtime)));
+                    None
+                }
+                StreamType::Bidirectional => Some(acceptor),
+            };
 
-                        // send the requested amount
-                        let total_sent = send_bytes_on_channel(&mut send, amount_to_send, quitting_receiver.clone()).await.unwrap();
+            if let Some(rate) = context.rate {
+                let seed = context.seed + connection_index as u64;
+                return open_loop(
+                    handle,
+                    StdRng::seed_from_u64(seed),
+                    rate,
+                    connection_counter,
+                    response_streams,
+                    context,
+                    quitting_receiver,
+                )
+                .await;
+            }
 
-                        let send_duration = Instant::now() - send_start;
+            let request_loops = (0..context.streams).map(|stream_index| {
+                let seed = context.seed + (connection_index * context.streams + stream_index) as u64;
+                context.runtime.spawn(request_loop(
+                    handle.clone(),
+                    StdRng::seed_from_u64(seed),
+                    connection_counter.clone(),
+                    response_streams.clone(),
+                    context.clone(),
+                    quitting_receiver.clone(),
+                ))
+            });
 
-                        send.close().await.unwrap();
+            return join_all(request_loops)
+                .await
+                .into_iter()
+                .filter_map(Result::ok)
+                .flatten()
+                .collect();
+        }
 
-                        info!("Sent {} @{}it/s",
-                            ByteSize(total_sent as u64).to_string_as(true),
-                            ByteSize((total_sent as f32 * 8f32 / send_duration.as_millis() as f32 * 1000f32) as u64).to_string_as(false)
-                        );
+        _ = quitting_receiver.changed() => {
+            info!("Received quit during connection setup, quitting.");
+            return Vec::new();
+        }
+    }
+}
 
-                        if *quitting_receiver.borrow() {
-                            break;
-                        }
+/// Closed-loop request stream, the next request starts once the previous one completed
+async fn request_loop(
+    handle: Handle,
+    mut rng: StdRng,
+    connection_counter: Option<Arc<TransferCounter>>,
+    response_streams: Arc<ResponseStreams>,
+    context: Arc<RequestLoopContext>,
+    mut quitting_receiver: watch::Receiver<bool>,
+) -> Vec<RequestRecord> {
+    let mut records = Vec::new();
 
-                        let receive_start_time = Instant::now();
+    loop {
+        if !context.request_limit.try_start() {
+            break;
+        }
 
-                        let received_data_bytes = read_all_from_channel(&mut recv, quitting_receiver.clone()).await.unwrap();
+        let amount_to_send = context.request_size.sample(&mut rng);
+        let amount_to_request = match sample_response_size(&context, &mut rng) {
+            Some(amount_to_request) => amount_to_request,
+            None => break,
+        };
 
-                        let receive_duration = Instant::now() - receive_start_time;
+        let record = match run_request(
+            handle.clone(),
+            connection_counter.clone(),
+            &response_streams,
+            &context,
+            amount_to_send,
+            amount_to_request,
+            context.runtime.now(),
+            quitting_receiver.clone(),
+        )
+        .await
+        {
+            Some(record) => record,
+            None => break,
+        };
 
-                        info!(
-                            "Rcvd {} @{}it/s",
-                            ByteSize(received_data_bytes as u64).to_string_as(true),
-                            ByteSize((received_data_bytes as f32 * 8f32 / receive_duration.as_millis() as f32 * 1000f32) as u64).to_string_as(false)
-                        );
+        let complete = has_complete_response(
+            &context,
+            &record,
+            amount_to_request,
+            *quitting_receiver.borrow(),
+        );
+        records.push(record);
 
-                        if received_data_bytes != amount_to_request as usize && !*quitting_receiver.borrow() {
-                            error!("Received mis-matching amount of response data! Received {} != {} requested!", received_data_bytes, amount_to_request);
-                            break 'request_loop;
-                        }
-                    },
-                    _ = quitting_receiver.changed() => {
-                        if *quitting_receiver.borrow() {
-                            info!("Received SIGINT, quitting.");
-                            break 'request_loop;
-                        }
+        if !complete {
+            break;
+        }
+
+        if let Some(think_time) = context.think_time {
+            let think_until = context.runtime.now() + think_time.sample(&mut rng);
+            tokio::select! {
+                biased;
+
+                _ = context.runtime.sleep_until(think_until) => {},
+                _ = quitting_receiver.changed() => {
+                    if *quitting_receiver.borrow() {
+                        break;
                     }
-                };
+                }
             }
         }
+    }
+
+    return records;
+}
+
+/// Open-loop request schedule, requests start at their scheduled time regardless of how many
+/// earlier requests are still outstanding
+async fn open_loop(
+    handle: Handle,
+    mut rng: StdRng,
+    rate: f64,
+    connection_counter: Option<Arc<TransferCounter>>,
+    response_streams: Arc<ResponseStreams>,
+    context: Arc<RequestLoopContext>,
+    mut quitting_receiver: watch::Receiver<bool>,
+) -> Vec<RequestRecord> {
+    let mut requests = FuturesUnordered::new();
+    let mut records = Vec::new();
+    let mut next_start = context.runtime.now();
+
+    loop {
+        tokio::select! {
+            biased;
+
+            _ = quitting_receiver.changed() => {
+                if *quitting_receiver.borrow() {
+                    break;
+                }
+                continue;
+            }
+            Some(request) = requests.next(), if !requests.is_empty() => {
+                // like the closed loop, stop the schedule once a response came back incomplete
+                if !collect_request(request, &mut records) {
+                    break;
+                }
+                continue;
+            }
+            _ = context.runtime.sleep_until(next_start) => {},
+        }
+
+        if !context.request_limit.try_start() {
+            break;
+        }
+
+        let amount_to_send = context.request_size.sample(&mut rng);
+        let amount_to_request = match sample_response_size(&context, &mut rng) {
+            Some(amount_to_request) => amount_to_request,
+            None => break,
+        };
+
+        let outstanding = context.outstanding_requests.fetch_add(1, Ordering::Relaxed) + 1;
+        context
+            .max_outstanding_requests
+            .fetch_max(outstanding, Ordering::Relaxed);
+
+        let handle = handle.clone();
+        let connection_counter = connection_counter.clone();
+        let response_streams = response_streams.clone();
+        let request_context = context.clone();
+        let quitting_receiver = quitting_receiver.clone();
+        // latencies are measured from the scheduled start, so a stalled connection can't hide the
+        // requests that queued up behind it (coordinated omission)
+        let scheduled_start = next_start;
+        requests.push(context.runtime.spawn(async move {
+            let record = run_request(
+                handle,
+                connection_counter,
+                &response_streams,
+                &request_context,
+                amount_to_send,
+                amount_to_request,
+                scheduled_start,
+                quitting_receiver.clone(),
+            )
+            .await;
+            request_context
+                .outstanding_requests
+                .fetch_sub(1, Ordering::Relaxed);
+
+            let complete = match &record {
+                Some(record) => has_complete_response(
+                    &request_context,
+                    record,
+                    amount_to_request,
+                    *quitting_receiver.borrow(),
+                ),
+                None => true,
+            };
+            (record, complete)
+        }));
+
+        next_start += context.arrival.interval(rate, &mut rng);
+    }
+
+    while let Some(request) = requests.next().await {
+        collect_request(request, &mut records);
+    }
+
+    return records;
+}
+
+/// Adds the record of a finished open-loop request to `records`, returns whether its response was
+/// complete
+fn collect_request(
+    request: Result<(Option<RequestRecord>, bool), Box<dyn Error + Send + Sync>>,
+    records: &mut Vec<RequestRecord>,
+) -> bool {
+    return match request {
+        Ok((record, complete)) => {
+            records.extend(record);
+            complete
+        }
+        Err(e) => {
+            error!("Request task failed: {}", e);
+            false
+        }
+    };
+}
+
+/// Draws the response size of the next request, `None` if an unbounded distribution produced the
+/// size reserved for echo requests
+fn sample_response_size(context: &RequestLoopContext, rng: &mut StdRng) -> Option<u64> {
+    let amount_to_request = context.response_size.sample(rng);
+
+    if context.mode != TrafficMode::Echo && amount_to_request == ECHO_RESPONSE_SIZE {
+        error!(
+            "Drew the response size {}, which is reserved for echo requests!",
+            amount_to_request
+        );
+        return None;
+    }
+
+    return Some(amount_to_request);
+}
+
+/// Checks that a completed request received the expected response size. Mismatches are ignored
+/// while quitting, because quitting cuts responses short.
+fn has_complete_response(
+    context: &RequestLoopContext,
+    record: &RequestRecord,
+    amount_to_request: u64,
+    quitting: bool,
+) -> bool {
+    let expected_bytes = match context.mode {
+        TrafficMode::Echo => record.request_bytes,
+        _ => amount_to_request,
+    };
+
+    if record.response_bytes == expected_bytes || quitting {
+        return true;
+    }
+
+    error!(
+        "Received mis-matching amount of response data! Received {} != {} requested!",
+        record.response_bytes, expected_bytes
+    );
+    return false;
+}
+
+/// Runs a single request on a new stream, returns `None` if the client quit before it completed.
+///
+/// `scheduled_start` is the time the request should have started, it is earlier than the actual
+/// start if the request had to wait for a free stream or for the open-loop schedule to catch up.
+#[allow(clippy::too_many_arguments)]
+async fn run_request(
+    mut handle: Handle,
+    connection_counter: Option<Arc<TransferCounter>>,
+    response_streams: &Arc<ResponseStreams>,
+    context: &RequestLoopContext,
+    amount_to_send: u64,
+    amount_to_request: u64,
+    scheduled_start: Instant,
+    mut quitting_receiver: watch::Receiver<bool>,
+) -> Option<RequestRecord> {
+    let open = async {
+        return match context.stream_type {
+            StreamType::Bidirectional => {
+                let (recv, send) = handle.open_bidirectional_stream().await.unwrap().split();
+                (send, ResponseSource::Stream(recv))
+            }
+            StreamType::Unidirectional => {
+                let send = handle.open_send_stream().await.unwrap();
+                // register before sending the request, so the response stream can't arrive first
+                let response_source = if context.mode == TrafficMode::Echo || amount_to_request > 0
+                {
+                    ResponseSource::Pushed(response_streams.register(send.id()))
+                } else {
+                    ResponseSource::Nothing
+                };
+                (send, response_source)
+            }
+        };
+    };
+
+    let (mut send, response_source) = tokio::select! {
+        biased;
 
+        opened = open => opened,
         _ = quitting_receiver.changed() => {
-            info!("Received quit during connection setup, quitting.");
+            info!("Received exit, quitting.");
+            return None;
         }
+    };
+
+    if *quitting_receiver.borrow() {
+        return None;
     }
 
-    return Ok(());
+    let stream_counter = context.interval_reporter.as_ref().map(|reporter| {
+        reporter.register(
+            format!("conn {} stream {}", handle.id(), send.id()),
+            connection_counter,
+        )
+    });
+
+    let runtime = context.runtime;
+    let request_start_time = runtime.system_time();
+    let queueing_delay = runtime.now() - scheduled_start;
+    let header = match context.mode {
+        TrafficMode::Echo => ECHO_RESPONSE_SIZE,
+        _ => amount_to_request,
+    };
+
+    let ((total_sent, send_duration), receive_start_time, receive_stats) = match context.mode {
+        TrafficMode::Bidirectional | TrafficMode::Echo => {
+            let receive_start_time = runtime.now();
+            let ((total_sent, send_duration, _), receive_stats) = tokio::join!(
+                send_request(
+                    &mut send,
+                    header,
+                    amount_to_send,
+                    stream_counter.as_deref(),
+                    quitting_receiver.clone(),
+                    runtime
+                ),
+                receive_response(
+                    response_source,
+                    stream_counter.as_deref(),
+                    quitting_receiver.clone(),
+                    runtime
+                ),
+            );
+            (
+                (total_sent, send_duration),
+                receive_start_time,
+                receive_stats,
+            )
+        }
+        TrafficMode::Sequential | TrafficMode::Upload | TrafficMode::Download => {
+            let (total_sent, send_duration, last_send_time) = send_request(
+                &mut send,
+                header,
+                amount_to_send,
+                stream_counter.as_deref(),
+                quitting_receiver.clone(),
+                runtime,
+            )
+            .await;
+
+            if *quitting_receiver.borrow() {
+                return None;
+            }
+
+            // the TTFB is measured from the last request byte, the server responds once it read it
+            let receive_stats = receive_response(
+                response_source,
+                stream_counter.as_deref(),
+                quitting_receiver.clone(),
+                runtime,
+            )
+            .await;
+            ((total_sent, send_duration), last_send_time, receive_stats)
+        }
+    };
+    let received_data_bytes = receive_stats.received_bytes;
+    let receive_duration = runtime.now() - receive_start_time;
+    let latency = runtime.now() - scheduled_start;
+
+    info!(
+        "Sent {} @{}it/s",
+        ByteSize(total_sent as u64).to_string_as(true),
+        ByteSize((total_sent as f32 * 8f32 / send_duration.as_millis() as f32 * 1000f32) as u64)
+            .to_string_as(false)
+    );
+
+    context.request_limit.complete();
+
+    let record = RequestRecord::new(
+        request_start_time,
+        handle.id(),
+        send.id(),
+        total_sent as u64,
+        send_duration,
+        receive_start_time,
+        receive_duration,
+        &receive_stats,
+        queueing_delay,
+        latency,
+    );
+
+    if let Some(request_logger) = &context.request_logger {
+        request_logger.log(&record);
+    }
+
+    // a response that arrived at once has no goodput
+    let goodput = match record.receive_goodput_bps {
+        Some(goodput) => format!("{}it/s", ByteSize(goodput as u64).to_string_as(false)),
+        None => "n/a".to_string(),
+    };
+    info!(
+        "Rcvd {} @{}it/s (TTFB {:?}, goodput {}, latency {:?})",
+        ByteSize(received_data_bytes as u64).to_string_as(true),
+        ByteSize(
+            (received_data_bytes as f32 * 8f32 / receive_duration.as_millis() as f32 * 1000f32)
+                as u64
+        )
+        .to_string_as(false),
+        record
+            .time_to_first_byte_ns
+            .map(Duration::from_nanos)
+            .unwrap_or_default(),
+        goodput,
+        latency
+    );
+    debug!(
+        "Received response in {} receive calls, chunk sizes {}",
+        receive_stats.receive_calls, receive_stats.chunk_sizes
+    );
+
+    return Some(record);
+}
+
+/// Reads a response until the server finished it, waiting for the server to open the response
+/// stream first for unidirectional requests
+async fn receive_response(
+    response_source: ResponseSource,
+    counter: Option<&TransferCounter>,
+    mut quitting_receiver: watch::Receiver<bool>,
+    runtime: Runtime,
+) -> ReceiveStats {
+    return match response_source {
+        ResponseSource::Stream(mut recv) => {
+            read_all_from_channel(&mut recv, counter, quitting_receiver, || runtime.now())
+                .await
+                .unwrap()
+        }
+        ResponseSource::Pushed(mut pending_response) => {
+            let mut response_stream = tokio::select! {
+                biased;
+
+                response_stream = &mut pending_response.receiver => match response_stream {
+                    Ok(response_stream) => response_stream,
+                    Err(_) => return ReceiveStats::default(),
+                },
+                _ = quitting_receiver.changed() => return ReceiveStats::default(),
+            };
+
+            let mut receive_stats = read_all_from_channel(
+                &mut response_stream.recv,
+                counter,
+                quitting_receiver,
+                || runtime.now(),
+            )
+            .await
+            .unwrap();
+            receive_stats.record_prefix(&response_stream.prefix, response_stream.received_at);
+            receive_stats
+        }
+        ResponseSource::Nothing => ReceiveStats::default(),
+    };
+}
+
+/// Accepts the server-initiated response streams of a connection and dispatches them by the
+/// request stream id at their start
+async fn accept_response_streams(
+    mut acceptor: StreamAcceptor,
+    response_streams: Arc<ResponseStreams>,
+    runtime: Runtime,
+) {
+    loop {
+        let mut recv = match acceptor.accept_receive_stream().await {
+            Ok(Some(recv)) => recv,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Stopped accepting response streams: {}", e);
+                break;
+            }
+        };
+
+        let response_streams = response_streams.clone();
+        drop(runtime.spawn(async move {
+            match read_header(&mut recv).await {
+                Ok((request_stream_id, prefix)) => response_streams.dispatch(
+                    request_stream_id,
+                    ResponseStream {
+                        recv,
+                        prefix,
+                        received_at: runtime.now(),
+                    },
+                ),
+                Err(e) => error!("Failed to read response stream header: {}", e),
+            }
+        }));
+    }
+}
+
+/// Sends the request header and `amount_to_send` bytes, then closes the send side.
+///
+/// Returns the number of bytes sent after the header, the time it took and the time the last byte
+/// was handed to the stream, before waiting for the close.
+async fn send_request(
+    send: &mut SendStream,
+    header: u64,
+    amount_to_send: u64,
+    counter: Option<&TransferCounter>,
+    quitting_receiver: watch::Receiver<bool>,
+    runtime: Runtime,
+) -> (usize, Duration, Instant) {
+    let send_start = runtime.now();
+
+    send.send(header.to_be_bytes().to_vec().into())
+        .await
+        .unwrap();
+
+    // send the requested amount
+    let total_sent = send_bytes_on_channel(send, amount_to_send, counter, quitting_receiver)
+        .await
+        .unwrap();
+
+    let last_send_time = runtime.now();
+    let send_duration = last_send_time - send_start;
+
+    // the request is finished but not closed: waiting for the server to acknowledge the upload
+    // would delay reading the response and inflate the TTFB
+    return (total_sent, send_duration, last_send_time);
 }
@@ -1,15 +1,69 @@
-use std::{error::Error};
+use std::{
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use s2n_quic::stream::{ReceiveStream, SendStream};
 use tokio::sync::watch;
 
+/// ALPN used by the perf client and server
+pub const APPLICATION_PROTOCOL: &str = "perf";
+
+/// Response size header value asking the server to echo the upload instead of sending a response
+pub const ECHO_RESPONSE_SIZE: u64 = u64::MAX;
+
+/// Byte counters for interval reporting, additions are propagated to the parent counter
+#[derive(Debug, Default)]
+pub struct TransferCounter {
+    sent: AtomicU64,
+    received: AtomicU64,
+    parent: Option<Arc<TransferCounter>>,
+}
+
+impl TransferCounter {
+    pub fn new(parent: Option<Arc<TransferCounter>>) -> Self {
+        Self {
+            parent,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.add_sent(bytes);
+        }
+    }
+
+    pub fn add_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.add_received(bytes);
+        }
+    }
+
+    /// Returns the sent and received bytes since the last call and resets the counters
+    pub fn take(&self) -> (u64, u64) {
+        return (
+            self.sent.swap(0, Ordering::Relaxed),
+            self.received.swap(0, Ordering::Relaxed),
+        );
+    }
+}
+
 pub async fn send_bytes_on_channel(
     send: &mut SendStream,
     to_send: u64,
+    counter: Option<&TransferCounter>,
     should_quit_receiver: watch::Receiver<bool>,
-) -> Result<usize, Box<dyn Error>> {
-    let mut data = s2n_quic_core::stream::testing::Data::new(to_send.try_into().unwrap());
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let mut data = s2n_quic_core::stream::testing::Data::new(to_send);
     let mut chunks = vec![Bytes::new(); 64];
 
     loop {
@@ -19,7 +73,12 @@ pub async fn send_bytes_on_channel(
 
         match data.send(usize::MAX, &mut chunks) {
             Some(count) => {
+                let chunk_bytes: usize = chunks[..count].iter().map(|chunk| chunk.len()).sum();
                 send.send_vectored(&mut chunks[..count]).await?;
+
+                if let Some(counter) = counter {
+                    counter.add_sent(chunk_bytes as u64);
+                }
             }
             None => {
                 send.finish()?;
@@ -31,11 +90,92 @@ pub async fn send_bytes_on_channel(
     return Ok(data.offset().try_into().unwrap());
 }
 
+/// Result of reading a stream until it is finished
+#[derive(Default)]
+pub struct ReceiveStats {
+    pub received_bytes: usize,
+    /// Time at which the first non-empty chunk was received
+    pub first_byte_time: Option<Instant>,
+    /// Time at which the last non-empty chunk was received
+    pub last_byte_time: Option<Instant>,
+    /// Number of `receive_vectored` calls until the stream finished
+    pub receive_calls: usize,
+    pub chunk_sizes: ChunkSizeHistogram,
+}
+
+impl ReceiveStats {
+    /// Accounts for data that was received together with a header, before the rest of the stream
+    /// was read
+    pub fn record_prefix(&mut self, prefix: &Bytes, received_at: Instant) {
+        if prefix.is_empty() {
+            return;
+        }
+
+        self.received_bytes += prefix.len();
+        self.chunk_sizes.record(prefix.len());
+        self.first_byte_time = Some(match self.first_byte_time {
+            Some(first_byte_time) => first_byte_time.min(received_at),
+            None => received_at,
+        });
+        self.last_byte_time.get_or_insert(received_at);
+    }
+
+    /// Duration between the first and the last received byte, excludes the time to first byte
+    pub fn transfer_duration(&self) -> Option<Duration> {
+        match (self.first_byte_time, self.last_byte_time) {
+            (Some(first), Some(last)) => Some(last - first),
+            _ => None,
+        }
+    }
+}
+
+/// Histogram of received chunk sizes with power-of-two buckets, bucket `i` counts chunks of
+/// `2^i..2^(i+1)` bytes
+#[derive(Debug, Default)]
+pub struct ChunkSizeHistogram {
+    buckets: Vec<u64>,
+}
+
+impl ChunkSizeHistogram {
+    fn record(&mut self, chunk_size: usize) {
+        if chunk_size == 0 {
+            return;
+        }
+
+        let bucket = (usize::BITS - 1 - chunk_size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+}
+
+impl fmt::Display for ChunkSizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| format!("{}B:{}", 1usize << bucket, count))
+            .collect::<Vec<_>>();
+
+        return write!(f, "[{}]", buckets.join(", "));
+    }
+}
+
+/// Reads a stream until it is finished, `clock` provides the receive times
 pub async fn read_all_from_channel(
     recv: &mut ReceiveStream,
+    counter: Option<&TransferCounter>,
     should_quit_receiver: watch::Receiver<bool>,
-) -> Result<usize, Box<dyn Error>> {
+    clock: impl Fn() -> Instant,
+) -> Result<ReceiveStats, Box<dyn Error + Send + Sync>> {
     let mut received_data_bytes = 0;
+    let mut first_byte_time = None;
+    let mut last_byte_time = None;
+    let mut receive_calls = 0;
+    let mut chunk_sizes = ChunkSizeHistogram::default();
 
     let mut chunks = vec![Bytes::new(); 64];
 
@@ -45,16 +185,63 @@ pub async fn read_all_from_channel(
         }
 
         let (len, is_open) = recv.receive_vectored(&mut chunks).await?;
+        receive_calls += 1;
+        let received_before = received_data_bytes;
 
         for chunk in chunks[..len].iter_mut() {
+            if !chunk.is_empty() {
+                let now = clock();
+                first_byte_time.get_or_insert(now);
+                last_byte_time = Some(now);
+            }
+
+            chunk_sizes.record(chunk.len());
             received_data_bytes += chunk.len();
             *chunk = Bytes::new();
         }
 
+        if let Some(counter) = counter {
+            counter.add_received((received_data_bytes - received_before) as u64);
+        }
+
         if !is_open {
             break;
         }
     }
 
-    return Ok(received_data_bytes);
+    return Ok(ReceiveStats {
+        received_bytes: received_data_bytes,
+        first_byte_time,
+        last_byte_time,
+        receive_calls,
+        chunk_sizes,
+    });
+}
+
+/// Reads an 8-byte big-endian header from the start of a stream, i.e. the response size of a
+/// request stream or the request stream id of a unidirectional response stream.
+///
+/// Returns the header and the stream data that arrived in the same chunk as the end of the header.
+pub async fn read_header(
+    recv: &mut ReceiveStream,
+) -> Result<(u64, Bytes), Box<dyn Error + Send + Sync>> {
+    let mut header = [0u8; 8];
+    let mut header_len = 0;
+
+    loop {
+        match recv.receive().await? {
+            Some(chunk) => {
+                let needed = (header.len() - header_len).min(chunk.len());
+                header[header_len..header_len + needed].copy_from_slice(&chunk[..needed]);
+                header_len += needed;
+
+                if header_len == header.len() {
+                    return Ok((u64::from_be_bytes(header), chunk.slice(needed..)));
+                }
+            }
+            None => {
+                return Err("Stream finished before the header was received".into());
+            }
+        }
+    }
 }
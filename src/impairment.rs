@@ -0,0 +1,325 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    error::Error,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytesize::ByteSize;
+use log::{debug, error, info};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{sleep_until, Instant},
+};
+
+/// Largest UDP datagram the relay forwards
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Network impairments applied by the in-process relay, in each direction independently.
+///
+/// The relay forwards real UDP packets in wall-clock time, so while its random decisions are
+/// seeded, when packets arrive and which peer gets which generator depend on the OS scheduler and
+/// runs differ. `--simulate` runs in simulated time and is reproducible.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ImpairmentConfig {
+    /// One-way delay added to every packet (e.g. `25ms`)
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    pub impair_delay: Option<Duration>,
+    /// Uniformly distributed jitter of up to +/- this duration on top of the delay
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    pub impair_jitter: Option<Duration>,
+    /// Bottleneck rate in Mbit/s
+    #[clap(long)]
+    pub impair_rate: Option<f64>,
+    /// Bottleneck queue size, packets exceeding it are tail-dropped
+    #[clap(long, default_value = "64KiB")]
+    pub impair_queue: ByteSize,
+    /// Random loss in percent
+    #[clap(long)]
+    pub impair_loss: Option<f64>,
+    /// Average burst loss in percent (Gilbert-Elliott model)
+    #[clap(long)]
+    pub impair_burst_loss: Option<f64>,
+    /// Mean length of a loss burst in packets
+    #[clap(long, default_value = "3")]
+    pub impair_burst_length: f64,
+    /// Percentage of packets sent without delay, overtaking the delayed ones
+    #[clap(long)]
+    pub impair_reorder: Option<f64>,
+    /// Percentage of packets sent twice
+    #[clap(long)]
+    pub impair_duplicate: Option<f64>,
+    /// Seed for the random impairment decisions
+    #[clap(long, default_value = "0")]
+    pub impair_seed: u64,
+}
+
+impl ImpairmentConfig {
+    /// Whether any impairment is configured, the relay is only started if so
+    pub fn is_enabled(&self) -> bool {
+        return self.impair_delay.is_some()
+            || self.impair_jitter.is_some()
+            || self.impair_rate.is_some()
+            || self.impair_loss.is_some()
+            || self.impair_burst_loss.is_some()
+            || self.impair_reorder.is_some()
+            || self.impair_duplicate.is_some();
+    }
+}
+
+/// Where a link delivers its packets to
+enum Destination {
+    /// Connected upstream socket
+    Connected(Arc<UdpSocket>),
+    /// Relay socket, answering the given peer
+    Peer(Arc<UdpSocket>, SocketAddr),
+}
+
+impl Destination {
+    async fn send(&self, payload: &[u8]) {
+        let result = match self {
+            Destination::Connected(socket) => socket.send(payload).await,
+            Destination::Peer(socket, addr) => socket.send_to(payload, addr).await,
+        };
+
+        if let Err(e) = result {
+            debug!("Impairment relay failed to forward a packet: {}", e);
+        }
+    }
+}
+
+/// One direction of the relay, decides when (and whether) every packet is delivered
+struct Link {
+    config: ImpairmentConfig,
+    rng: StdRng,
+    /// Time the bottleneck finishes serializing the queued packets
+    bottleneck_free: Instant,
+    /// Gilbert-Elliott state, `true` while in a loss burst
+    burst: bool,
+    scheduled: UnboundedSender<(Instant, Vec<u8>)>,
+}
+
+impl Link {
+    fn new(config: ImpairmentConfig, seed: u64, destination: Destination) -> Self {
+        let (scheduled, scheduled_receiver) = unbounded_channel();
+        tokio::spawn(deliver(scheduled_receiver, destination));
+
+        Self {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            bottleneck_free: Instant::now(),
+            burst: false,
+            scheduled,
+        }
+    }
+
+    fn chance(&mut self, percent: Option<f64>) -> bool {
+        return match percent {
+            Some(percent) => self.rng.gen_bool((percent / 100f64).clamp(0f64, 1f64)),
+            None => false,
+        };
+    }
+
+    fn is_lost(&mut self) -> bool {
+        if let Some(burst_loss) = self.config.impair_burst_loss {
+            // choose the transition probabilities so that the share of time spent in a burst
+            // matches the configured loss and bursts have the configured mean length
+            let exit = 1f64 / self.config.impair_burst_length.max(1f64);
+            let loss = (burst_loss / 100f64).clamp(0f64, 0.99);
+            let enter = (loss * exit / (1f64 - loss)).min(1f64);
+
+            self.burst = if self.burst {
+                !self.rng.gen_bool(exit)
+            } else {
+                self.rng.gen_bool(enter)
+            };
+            if self.burst {
+                return true;
+            }
+        }
+
+        return self.chance(self.config.impair_loss);
+    }
+
+    fn forward(&mut self, payload: Vec<u8>) {
+        if self.is_lost() {
+            return;
+        }
+
+        // a duplicate is a packet of its own, it passes the bottleneck and its queue limit too
+        if self.chance(self.config.impair_duplicate) {
+            self.enqueue(payload.clone());
+        }
+        self.enqueue(payload);
+    }
+
+    /// Passes a packet through the bottleneck and schedules its delivery, unless the bottleneck
+    /// queue is full
+    fn enqueue(&mut self, payload: Vec<u8>) {
+        let now = Instant::now();
+        let mut departure = now;
+
+        if let Some(rate) = self.config.impair_rate {
+            let bytes_per_sec = rate * 1_000_000f64 / 8f64;
+            let backlog = self.bottleneck_free.saturating_duration_since(now);
+            let queued_bytes = backlog.as_secs_f64() * bytes_per_sec;
+
+            if queued_bytes + payload.len() as f64 > self.config.impair_queue.as_u64() as f64 {
+                return;
+            }
+
+            let serialization = Duration::from_secs_f64(payload.len() as f64 / bytes_per_sec);
+            departure = self.bottleneck_free.max(now) + serialization;
+            self.bottleneck_free = departure;
+        }
+
+        let deliver_at = if self.chance(self.config.impair_reorder) {
+            departure
+        } else {
+            departure + self.delay()
+        };
+
+        let _ = self.scheduled.send((deliver_at, payload));
+    }
+
+    fn delay(&mut self) -> Duration {
+        let delay = self.config.impair_delay.unwrap_or_default();
+
+        return match self.config.impair_jitter {
+            Some(jitter) if !jitter.is_zero() => {
+                let offset = self
+                    .rng
+                    .gen_range(-jitter.as_secs_f64()..=jitter.as_secs_f64());
+                Duration::from_secs_f64((delay.as_secs_f64() + offset).max(0f64))
+            }
+            _ => delay,
+        };
+    }
+}
+
+/// Sends the scheduled packets of a link once their delivery time is reached
+async fn deliver(
+    mut scheduled_receiver: UnboundedReceiver<(Instant, Vec<u8>)>,
+    destination: Destination,
+) {
+    // the sequence number keeps packets with equal delivery times in order
+    let mut queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>> = BinaryHeap::new();
+    let mut sequence = 0u64;
+
+    loop {
+        let next = queue.peek().map(|Reverse((deliver_at, _, _))| *deliver_at);
+
+        tokio::select! {
+            scheduled = scheduled_receiver.recv() => {
+                match scheduled {
+                    Some((deliver_at, payload)) => {
+                        queue.push(Reverse((deliver_at, sequence, payload)));
+                        sequence += 1;
+                    }
+                    None => return,
+                }
+            }
+            _ = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let Reverse((_, _, payload)) = queue.pop().unwrap();
+                destination.send(&payload).await;
+            }
+        }
+    }
+}
+
+/// Starts a UDP relay on loopback that forwards to `remote` through the configured impairments.
+/// Every local peer gets its own upstream socket, so separate endpoints stay distinguishable.
+/// Returns the address to connect to instead of `remote`.
+pub async fn start_relay(
+    remote: SocketAddr,
+    config: ImpairmentConfig,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_addr = socket.local_addr()?;
+
+    info!(
+        "Impairment relay listening on {} forwarding to {} ({:?}).",
+        relay_addr, remote, config
+    );
+
+    tokio::spawn(async move {
+        let mut upstream_links: HashMap<SocketAddr, Link> = HashMap::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Impairment relay failed to receive: {}", e);
+                    return;
+                }
+            };
+
+            if !upstream_links.contains_key(&peer) {
+                // every direction of every peer draws from its own seeded generator
+                let seed = config.impair_seed + 2 * upstream_links.len() as u64;
+                match start_upstream(remote, peer, socket.clone(), config.clone(), seed).await {
+                    Ok(link) => {
+                        upstream_links.insert(peer, link);
+                    }
+                    Err(e) => {
+                        error!("Impairment relay failed to connect {}: {}", peer, e);
+                        continue;
+                    }
+                }
+            }
+
+            upstream_links
+                .get_mut(&peer)
+                .unwrap()
+                .forward(buf[..len].to_vec());
+        }
+    });
+
+    return Ok(relay_addr);
+}
+
+/// Connects an upstream socket for `peer` and spawns the downstream direction, returns the
+/// upstream link
+async fn start_upstream(
+    remote: SocketAddr,
+    peer: SocketAddr,
+    relay_socket: Arc<UdpSocket>,
+    config: ImpairmentConfig,
+    seed: u64,
+) -> Result<Link, Box<dyn Error>> {
+    let bind_addr = if remote.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let upstream_socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+    upstream_socket.connect(remote).await?;
+
+    let upstream_link = Link::new(
+        config.clone(),
+        seed,
+        Destination::Connected(upstream_socket.clone()),
+    );
+    let mut downstream_link = Link::new(config, seed + 1, Destination::Peer(relay_socket, peer));
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            match upstream_socket.recv(&mut buf).await {
+                Ok(len) => downstream_link.forward(buf[..len].to_vec()),
+                Err(e) => {
+                    debug!("Impairment relay stopped receiving for {}: {}", peer, e);
+                    return;
+                }
+            }
+        }
+    });
+
+    return Ok(upstream_link);
+}
@@ -0,0 +1,71 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytesize::ByteSize;
+use log::info;
+use tokio::sync::watch;
+
+use crate::common::TransferCounter;
+
+/// Periodically logs the bytes transferred in the last interval for every registered counter,
+/// similar to iperf's interval reports.
+#[derive(Default)]
+pub struct IntervalReporter {
+    counters: Mutex<Vec<(String, Arc<TransferCounter>)>>,
+}
+
+impl IntervalReporter {
+    /// Registers a new counter, it is reported one final time after all other references to it
+    /// have been dropped.
+    pub fn register(
+        &self,
+        label: String,
+        parent: Option<Arc<TransferCounter>>,
+    ) -> Arc<TransferCounter> {
+        let counter = Arc::new(TransferCounter::new(parent));
+        self.counters.lock().unwrap().push((label, counter.clone()));
+
+        return counter;
+    }
+
+    pub async fn run(&self, interval: Duration, mut quitting_receiver: watch::Receiver<bool>) {
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick completes immediately
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    self.report(interval);
+                },
+                _ = quitting_receiver.changed() => {
+                    if *quitting_receiver.borrow() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn report(&self, interval: Duration) {
+        let mut counters = self.counters.lock().unwrap();
+
+        for (label, counter) in counters.iter() {
+            let (sent, received) = counter.take();
+
+            info!(
+                "[{}] sent {} @{}it/s, rcvd {} @{}it/s",
+                label,
+                ByteSize(sent).to_string_as(true),
+                ByteSize((sent as f64 * 8f64 / interval.as_secs_f64()) as u64).to_string_as(false),
+                ByteSize(received).to_string_as(true),
+                ByteSize((received as f64 * 8f64 / interval.as_secs_f64()) as u64)
+                    .to_string_as(false),
+            );
+        }
+
+        counters.retain(|(_, counter)| Arc::strong_count(counter) > 1);
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use log::{error, info, warn};
+use serde_json::Value;
+
+use crate::record_writer::{EventRecord, MetricsRecord, RecordWriter};
+
+/// Maximum number of messages queued for the writer thread before records are dropped or, for
+/// control messages and a lossless writer, queueing blocks
+const QUEUE_CAPACITY: usize = 1 << 16;
+
+/// Record separator that starts every record of a JSON-SEQ file (RFC 7464)
+const RECORD_SEPARATOR: u8 = 0x1E;
+
+/// File written by the writer thread
+pub enum LogOutput {
+    /// CC log in the selected encoding
+    Metrics(Box<dyn RecordWriter>),
+    /// CSV event log
+    Events(Box<dyn Write + Send>),
+    /// qlog file in the JSON-SEQ format
+    Qlog(Box<dyn Write + Send>),
+}
+
+impl LogOutput {
+    fn write(&mut self, message: OutputMessage) -> io::Result<()> {
+        return match (self, message) {
+            (LogOutput::Metrics(writer), OutputMessage::Metadata(metadata)) => {
+                writer.write_metadata(&metadata)
+            }
+            (LogOutput::Metrics(writer), OutputMessage::Header) => writer.write_header(),
+            (LogOutput::Metrics(writer), OutputMessage::Record(record)) => {
+                writer.write_record(&record)
+            }
+            (LogOutput::Events(writer), OutputMessage::Header) => {
+                writeln!(writer, "{}", EventRecord::HEADER.join(","))
+            }
+            (LogOutput::Events(writer), OutputMessage::Event(event)) => event.write_csv(writer),
+            (LogOutput::Qlog(writer), OutputMessage::Qlog(record)) => {
+                writer.write_all(&[RECORD_SEPARATOR])?;
+                serde_json::to_writer(&mut *writer, &record)?;
+                writeln!(writer)
+            }
+            _ => Ok(()),
+        };
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return match self {
+            LogOutput::Metrics(writer) => writer.flush(),
+            LogOutput::Events(writer) | LogOutput::Qlog(writer) => writer.flush(),
+        };
+    }
+}
+
+/// Creates a `LogOutput` on the writer thread, so creating files stays off the event path too
+type OpenOutput = Box<dyn FnOnce() -> io::Result<LogOutput> + Send>;
+
+enum Message {
+    /// Creates the output of a sink, `name` is used in error messages
+    Open {
+        sink: u64,
+        name: String,
+        open: OpenOutput,
+    },
+    Output {
+        sink: u64,
+        message: OutputMessage,
+    },
+    /// Flushes and closes the output of a sink
+    Close {
+        sink: u64,
+    },
+    Shutdown,
+}
+
+enum OutputMessage {
+    /// `key=value` connection metadata
+    Metadata(String),
+    Header,
+    Record(MetricsRecord),
+    Event(EventRecord),
+    Qlog(Value),
+}
+
+/// Output of a sink, `None` once writing it failed
+struct OpenedOutput {
+    name: String,
+    output: Option<LogOutput>,
+}
+
+impl OpenedOutput {
+    fn write(&mut self, message: OutputMessage) {
+        if let Some(output) = &mut self.output {
+            if let Err(e) = output.write(message) {
+                error!(
+                    "Failed to write {}, discarding further records: {}",
+                    self.name, e
+                );
+                self.output = None;
+            }
+        }
+    }
+
+    fn close(mut self) {
+        if let Some(output) = &mut self.output {
+            if let Err(e) = output.flush() {
+                error!("Failed to flush {}: {}", self.name, e);
+            }
+        }
+    }
+}
+
+/// Hands the records of all CC, event and qlog files of the process to one dedicated writer
+/// thread through a bounded queue, so that neither formatting nor file I/O happens on the QUIC
+/// endpoints' event path.
+///
+/// Records are queued without blocking, they are dropped and counted if the queue is full. A
+/// lossless writer waits for queue capacity instead, which only suits simulated time. Opening and
+/// closing an output, metadata and headers always wait for capacity, so a file is never created
+/// without its header or left open.
+/// Clones share the writer thread, which is stopped by `WriterThread::shutdown`.
+#[derive(Clone)]
+pub struct AsyncMetricsWriter {
+    sender: SyncSender<Message>,
+    lossless: bool,
+    next_sink: Arc<AtomicU64>,
+    dropped_records: Arc<AtomicU64>,
+}
+
+/// Handle to stop the writer thread of an `AsyncMetricsWriter`
+pub struct WriterThread {
+    sender: SyncSender<Message>,
+    thread: JoinHandle<()>,
+    dropped_records: Arc<AtomicU64>,
+}
+
+/// One output file of an `AsyncMetricsWriter`, the file is closed once the sink is dropped
+pub struct LogSink {
+    writer: AsyncMetricsWriter,
+    sink: u64,
+}
+
+impl AsyncMetricsWriter {
+    pub fn spawn(lossless: bool) -> (Self, WriterThread) {
+        let (sender, receiver) = sync_channel::<Message>(QUEUE_CAPACITY);
+        let dropped_records = Arc::new(AtomicU64::new(0));
+
+        let thread = thread::spawn(move || write_messages(receiver));
+
+        let writer = Self {
+            sender: sender.clone(),
+            lossless,
+            next_sink: Arc::new(AtomicU64::new(0)),
+            dropped_records: dropped_records.clone(),
+        };
+        let writer_thread = WriterThread {
+            sender,
+            thread,
+            dropped_records,
+        };
+
+        return (writer, writer_thread);
+    }
+
+    /// Adds an output, `open` is called on the writer thread to create it
+    pub fn open(
+        &self,
+        name: String,
+        open: impl FnOnce() -> io::Result<LogOutput> + Send + 'static,
+    ) -> LogSink {
+        let sink = self.next_sink.fetch_add(1, Ordering::Relaxed);
+        self.send(Message::Open {
+            sink,
+            name,
+            open: Box::new(open),
+        });
+
+        return LogSink {
+            writer: self.clone(),
+            sink,
+        };
+    }
+
+    /// Queues a message that must not be dropped, waiting for queue capacity if needed
+    fn send(&self, message: Message) {
+        // only fails if the writer thread is gone
+        if self.sender.send(message).is_err() {
+            self.dropped_records.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Queues a record, which is dropped if the queue is full unless the writer is lossless
+    fn try_send(&self, message: Message) {
+        if self.lossless {
+            self.send(message);
+            return;
+        }
+
+        // fails if the queue is full or the writer thread is gone
+        if self.sender.try_send(message).is_err() {
+            self.dropped_records.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl LogSink {
+    /// Queues a `key=value` metadata entry, only valid before the header
+    pub fn push_metadata(&self, metadata: String) {
+        self.writer
+            .send(self.output(OutputMessage::Metadata(metadata)));
+    }
+
+    pub fn push_header(&self) {
+        self.writer.send(self.output(OutputMessage::Header));
+    }
+
+    pub fn push(&self, record: MetricsRecord) {
+        self.writer
+            .try_send(self.output(OutputMessage::Record(record)));
+    }
+
+    pub fn push_event(&self, event: EventRecord) {
+        self.writer
+            .try_send(self.output(OutputMessage::Event(event)));
+    }
+
+    pub fn push_qlog(&self, record: Value) {
+        self.writer
+            .try_send(self.output(OutputMessage::Qlog(record)));
+    }
+
+    fn output(&self, message: OutputMessage) -> Message {
+        return Message::Output {
+            sink: self.sink,
+            message,
+        };
+    }
+}
+
+impl Drop for LogSink {
+    fn drop(&mut self) {
+        self.writer.send(Message::Close { sink: self.sink });
+    }
+}
+
+impl WriterThread {
+    /// Writes everything queued so far, flushes all outputs and stops the writer thread.
+    ///
+    /// Records queued after the shutdown are dropped.
+    pub fn shutdown(self) {
+        // waits for queue capacity, this runs once at the end of the process
+        let _ = self.sender.send(Message::Shutdown);
+        let _ = self.thread.join();
+
+        let dropped_records = self.dropped_records.load(Ordering::Relaxed);
+        if dropped_records > 0 {
+            warn!(
+                "Dropped {} log records because the writer queue was full.",
+                dropped_records
+            );
+        }
+        info!("Flushed logfile writer.");
+    }
+}
+
+fn write_messages(receiver: Receiver<Message>) {
+    let mut outputs = HashMap::<u64, OpenedOutput>::new();
+
+    for message in receiver {
+        match message {
+            Message::Open { sink, name, open } => {
+                let output = match open() {
+                    Ok(output) => Some(output),
+                    Err(e) => {
+                        error!("Failed to create {}: {}", name, e);
+                        None
+                    }
+                };
+                outputs.insert(sink, OpenedOutput { name, output });
+            }
+            Message::Output { sink, message } => {
+                if let Some(output) = outputs.get_mut(&sink) {
+                    output.write(message);
+                }
+            }
+            Message::Close { sink } => {
+                if let Some(output) = outputs.remove(&sink) {
+                    output.close();
+                }
+            }
+            Message::Shutdown => break,
+        }
+    }
+
+    for (_, output) in outputs.drain() {
+        output.close();
+    }
+}
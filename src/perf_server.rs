@@ -0,0 +1,235 @@
+use std::{error::Error, sync::Arc, time::Instant};
+
+use bytes::Bytes;
+use bytesize::ByteSize;
+use futures_util::future::BoxFuture;
+use log::{debug, error, info};
+use s2n_quic::{
+    connection::Handle,
+    stream::{BidirectionalStream, PeerStream, ReceiveStream, SendStream},
+    Connection,
+};
+use tokio::sync::watch;
+
+use crate::{
+    common::{
+        read_all_from_channel, read_header, send_bytes_on_channel, TransferCounter,
+        ECHO_RESPONSE_SIZE,
+    },
+    interval_reporter::IntervalReporter,
+};
+
+/// Serves perf requests on all streams of an accepted connection until it closes. Requests on
+/// unidirectional streams are answered on a new server-initiated unidirectional stream.
+///
+/// Every stream is served by a task started with `spawn`, so the server runs on tokio as well as
+/// in the client's simulation.
+pub async fn handle_connection(
+    connection: Connection,
+    interval_reporter: Option<Arc<IntervalReporter>>,
+    quitting_receiver: watch::Receiver<bool>,
+    spawn: fn(BoxFuture<'static, ()>),
+) {
+    let connection_id = connection.id();
+    info!("Accepted connection {}.", connection_id);
+    let connection_counter = interval_reporter
+        .as_ref()
+        .map(|reporter| reporter.register(format!("conn {}", connection_id), None));
+
+    let (handle, mut acceptor) = connection.split();
+
+    loop {
+        let stream = match acceptor.accept().await {
+            Ok(Some(stream)) => stream,
+            Ok(None) => {
+                info!("Connection closed.");
+                break;
+            }
+            Err(e) => {
+                info!("Connection closed with error: {}", e);
+                break;
+            }
+        };
+
+        let stream_counter = interval_reporter.as_ref().map(|reporter| {
+            reporter.register(
+                format!("conn {} stream {}", connection_id, stream.id()),
+                connection_counter.clone(),
+            )
+        });
+        let quitting_receiver = quitting_receiver.clone();
+
+        match stream {
+            PeerStream::Bidirectional(stream) => {
+                spawn(Box::pin(async move {
+                    if let Err(e) = handle_stream(stream, stream_counter, quitting_receiver).await {
+                        error!("Failed to handle request stream: {}", e);
+                    }
+                }));
+            }
+            PeerStream::Receive(stream) => {
+                let handle = handle.clone();
+                spawn(Box::pin(async move {
+                    if let Err(e) =
+                        handle_receive_stream(stream, handle, stream_counter, quitting_receiver)
+                            .await
+                    {
+                        error!("Failed to handle unidirectional request stream: {}", e);
+                    }
+                }));
+            }
+        }
+    }
+}
+
+async fn handle_stream(
+    stream: BidirectionalStream,
+    stream_counter: Option<Arc<TransferCounter>>,
+    quitting_receiver: watch::Receiver<bool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (mut recv, mut send) = stream.split();
+
+    let (amount_to_send, header_payload) = read_header(&mut recv).await?;
+
+    serve_request(
+        &mut recv,
+        &mut send,
+        amount_to_send,
+        header_payload,
+        stream_counter.as_deref(),
+        quitting_receiver,
+    )
+    .await?;
+
+    return Ok(());
+}
+
+/// Serves a request on a client-initiated unidirectional stream. The response goes out on a new
+/// unidirectional stream that starts with the 8-byte big-endian id of the request stream, no
+/// stream is opened if there is nothing to respond.
+async fn handle_receive_stream(
+    mut recv: ReceiveStream,
+    mut handle: Handle,
+    stream_counter: Option<Arc<TransferCounter>>,
+    quitting_receiver: watch::Receiver<bool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let request_stream_id = recv.id();
+    let (amount_to_send, header_payload) = read_header(&mut recv).await?;
+    let header_time = Instant::now();
+
+    if amount_to_send == 0 {
+        let mut receive_stats = read_all_from_channel(
+            &mut recv,
+            stream_counter.as_deref(),
+            quitting_receiver,
+            Instant::now,
+        )
+        .await?;
+        receive_stats.record_prefix(&header_payload, header_time);
+
+        info!(
+            "Rcvd {} on unidirectional stream {}, no response requested",
+            ByteSize(receive_stats.received_bytes as u64).to_string_as(true),
+            request_stream_id,
+        );
+        return Ok(());
+    }
+
+    let mut send = handle.open_send_stream().await?;
+    send.send(request_stream_id.to_be_bytes().to_vec().into())
+        .await?;
+
+    serve_request(
+        &mut recv,
+        &mut send,
+        amount_to_send,
+        header_payload,
+        stream_counter.as_deref(),
+        quitting_receiver,
+    )
+    .await?;
+
+    return Ok(());
+}
+
+/// Receives the rest of a request and sends the response, or echoes the request back if
+/// `amount_to_send` is `ECHO_RESPONSE_SIZE`.
+///
+/// The response is finished when this returns, closing `send` again fails once the client read
+/// all of it and the stream was freed.
+async fn serve_request(
+    recv: &mut ReceiveStream,
+    send: &mut SendStream,
+    amount_to_send: u64,
+    header_payload: Bytes,
+    counter: Option<&TransferCounter>,
+    quitting_receiver: watch::Receiver<bool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if amount_to_send == ECHO_RESPONSE_SIZE {
+        let echoed = echo_stream(recv, send, header_payload, counter, quitting_receiver).await?;
+        info!("Echoed {}", ByteSize(echoed as u64).to_string_as(true));
+        return Ok(());
+    }
+
+    let header_time = Instant::now();
+
+    // respond while the upload is still running, so both directions are busy at the same time
+    let (receive_stats, sent) = tokio::join!(
+        read_all_from_channel(recv, counter, quitting_receiver.clone(), Instant::now),
+        send_bytes_on_channel(send, amount_to_send, counter, quitting_receiver),
+    );
+    let mut receive_stats = receive_stats?;
+    sent?;
+
+    receive_stats.record_prefix(&header_payload, header_time);
+    let received_data_bytes = receive_stats.received_bytes;
+
+    info!(
+        "Rcvd {}, responded with {}",
+        ByteSize(received_data_bytes as u64).to_string_as(true),
+        ByteSize(amount_to_send).to_string_as(true),
+    );
+    debug!(
+        "Received upload in {} receive calls over {:?}, chunk sizes {}",
+        receive_stats.receive_calls,
+        receive_stats.transfer_duration().unwrap_or_default(),
+        receive_stats.chunk_sizes
+    );
+
+    return Ok(());
+}
+
+/// Sends every received chunk straight back until the peer finishes its side of the stream.
+///
+/// Returns the number of echoed bytes.
+async fn echo_stream(
+    recv: &mut ReceiveStream,
+    send: &mut SendStream,
+    header_payload: Bytes,
+    counter: Option<&TransferCounter>,
+    should_quit_receiver: watch::Receiver<bool>,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let mut echoed = 0;
+    let mut next_chunk = Some(header_payload);
+
+    while let Some(chunk) = next_chunk {
+        if *should_quit_receiver.borrow() {
+            break;
+        }
+
+        if !chunk.is_empty() {
+            echoed += chunk.len();
+            if let Some(counter) = counter {
+                counter.add_received(chunk.len() as u64);
+                counter.add_sent(chunk.len() as u64);
+            }
+            send.send(chunk).await?;
+        }
+
+        next_chunk = recv.receive().await?;
+    }
+
+    send.finish()?;
+
+    return Ok(echoed);
+}
@@ -0,0 +1,178 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use s2n_quic::provider::event::{
+    self,
+    events::{PacketHeader, PacketLost, PacketReceived, PacketSent, RecoveryMetrics},
+    ConnectionMeta,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    metrics_writer::{AsyncMetricsWriter, LogOutput, LogSink},
+    recovery_metrics_logger::packet_number,
+};
+
+/// Writes one qlog (JSON-SEQ) file per connection into `qlog_dir`, loadable by qvis
+pub struct QlogLogger {
+    qlog_dir: PathBuf,
+    /// Writer thread shared by all logfiles of the process
+    writer: AsyncMetricsWriter,
+}
+
+pub struct QlogConnectionContext {
+    writer: LogSink,
+    /// Connection start relative to the endpoint start, qlog event times are relative to this
+    start: Duration,
+}
+
+impl QlogLogger {
+    pub fn new(qlog_dir: PathBuf, writer: AsyncMetricsWriter) -> Self {
+        Self { qlog_dir, writer }
+    }
+}
+
+impl QlogConnectionContext {
+    fn log(&mut self, meta: &ConnectionMeta, name: &str, data: Value) {
+        let time = meta
+            .timestamp
+            .duration_since_start()
+            .saturating_sub(self.start);
+        let record = json!({
+            "time": millis(time),
+            "name": name,
+            "data": data,
+        });
+
+        self.writer.push_qlog(record);
+    }
+}
+
+fn packet_header(header: &PacketHeader) -> Value {
+    let packet_type = match header {
+        PacketHeader::Initial { .. } => "initial",
+        PacketHeader::Handshake { .. } => "handshake",
+        PacketHeader::ZeroRtt { .. } => "0RTT",
+        PacketHeader::OneRtt { .. } => "1RTT",
+        PacketHeader::Retry { .. } => "retry",
+        PacketHeader::VersionNegotiation { .. } => "version_negotiation",
+        _ => "unknown",
+    };
+
+    return match packet_number(header) {
+        Some(packet_number) => {
+            json!({ "packet_type": packet_type, "packet_number": packet_number })
+        }
+        None => json!({ "packet_type": packet_type }),
+    };
+}
+
+fn millis(duration: Duration) -> f64 {
+    return duration.as_secs_f64() * 1000f64;
+}
+
+impl event::Subscriber for QlogLogger {
+    type ConnectionContext = QlogConnectionContext;
+
+    fn create_connection_context(
+        &mut self,
+        meta: &event::ConnectionMeta,
+        _info: &event::ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        let vantage_point = format!("{:?}", meta.endpoint_type).to_lowercase();
+        let path = self
+            .qlog_dir
+            .join(format!("{}-{}.sqlog", vantage_point, meta.id));
+
+        // the file is created on the writer thread, off the event path
+        let writer = self
+            .writer
+            .open(format!("qlog file {}", path.display()), move || {
+                let file = File::create(&path)?;
+                return Ok(LogOutput::Qlog(Box::new(BufWriter::new(file))));
+            });
+
+        let reference_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        writer.push_qlog(json!({
+            "qlog_version": "0.3",
+            "qlog_format": "JSON-SEQ",
+            "title": format!("custom-perf {} connection {}", vantage_point, meta.id),
+            "trace": {
+                "vantage_point": { "type": vantage_point },
+                "common_fields": {
+                    "time_format": "relative",
+                    "reference_time": millis(reference_time),
+                },
+            },
+        }));
+
+        return QlogConnectionContext {
+            writer,
+            start: meta.timestamp.duration_since_start(),
+        };
+    }
+
+    fn on_recovery_metrics(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &RecoveryMetrics,
+    ) {
+        context.log(
+            meta,
+            "recovery:metrics_updated",
+            json!({
+                "min_rtt": millis(event.min_rtt),
+                "smoothed_rtt": millis(event.smoothed_rtt),
+                "latest_rtt": millis(event.latest_rtt),
+                "rtt_variance": millis(event.rtt_variance),
+                "pto_count": event.pto_count,
+                "congestion_window": event.congestion_window,
+                "bytes_in_flight": event.bytes_in_flight,
+            }),
+        );
+    }
+
+    fn on_packet_lost(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &PacketLost,
+    ) {
+        context.log(
+            meta,
+            "recovery:packet_lost",
+            json!({ "header": packet_header(&event.packet_header) }),
+        );
+    }
+
+    fn on_packet_sent(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &PacketSent,
+    ) {
+        context.log(
+            meta,
+            "transport:packet_sent",
+            json!({ "header": packet_header(&event.packet_header) }),
+        );
+    }
+
+    fn on_packet_received(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &PacketReceived,
+    ) {
+        context.log(
+            meta,
+            "transport:packet_received",
+            json!({ "header": packet_header(&event.packet_header) }),
+        );
+    }
+}
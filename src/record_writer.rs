@@ -0,0 +1,448 @@
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::ArgEnum;
+
+/// A single `RecoveryMetrics` row of the CC log
+pub struct MetricsRecord {
+    pub time: u128,
+    pub conn_id: u64,
+    pub min_rtt: Duration,
+    pub smoothed_rtt: Duration,
+    pub latest_rtt: Duration,
+    pub rtt_variance: Duration,
+    pub max_ack_delay: Duration,
+    pub pto_count: u32,
+    pub congestion_window: u32,
+    pub bytes_in_flight: u32,
+}
+
+/// A typed row of the CC event log
+pub struct EventRecord {
+    pub time: u128,
+    pub conn_id: u64,
+    pub event: &'static str,
+    pub packet_number: Option<u64>,
+    pub bytes: Option<usize>,
+    pub detail: String,
+}
+
+impl EventRecord {
+    pub const HEADER: [&'static str; 6] = [
+        "time",
+        "conn_id",
+        "event",
+        "packet_number",
+        "bytes",
+        "detail",
+    ];
+
+    pub fn write_csv(&self, writer: &mut dyn Write) -> io::Result<()> {
+        return writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            self.time,
+            self.conn_id,
+            self.event,
+            self.packet_number
+                .map(|pn| pn.to_string())
+                .unwrap_or_default(),
+            self.bytes
+                .map(|bytes| bytes.to_string())
+                .unwrap_or_default(),
+            // keep the detail in a single CSV column
+            self.detail.replace(',', ";")
+        );
+    }
+}
+
+/// Column of the CC log, durations are written in nanoseconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricsField {
+    Time,
+    ConnId,
+    MinRtt,
+    SmoothedRtt,
+    LatestRtt,
+    RttVariance,
+    MaxAckDelay,
+    PtoCount,
+    CongestionWindow,
+    BytesInFlight,
+}
+
+impl MetricsField {
+    pub const ALL: [MetricsField; 10] = [
+        MetricsField::Time,
+        MetricsField::ConnId,
+        MetricsField::MinRtt,
+        MetricsField::SmoothedRtt,
+        MetricsField::LatestRtt,
+        MetricsField::RttVariance,
+        MetricsField::MaxAckDelay,
+        MetricsField::PtoCount,
+        MetricsField::CongestionWindow,
+        MetricsField::BytesInFlight,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            MetricsField::Time => "time",
+            MetricsField::ConnId => "conn_id",
+            MetricsField::MinRtt => "min_rtt",
+            MetricsField::SmoothedRtt => "smoothed_rtt",
+            MetricsField::LatestRtt => "latest_rtt",
+            MetricsField::RttVariance => "rtt_variance",
+            MetricsField::MaxAckDelay => "max_ack_delay",
+            MetricsField::PtoCount => "pto_count",
+            MetricsField::CongestionWindow => "congestion_window",
+            MetricsField::BytesInFlight => "bytes_in_flight",
+        };
+    }
+
+    fn value(&self, record: &MetricsRecord) -> u64 {
+        return match self {
+            MetricsField::Time => record.time as u64,
+            MetricsField::ConnId => record.conn_id,
+            MetricsField::MinRtt => record.min_rtt.as_nanos() as u64,
+            MetricsField::SmoothedRtt => record.smoothed_rtt.as_nanos() as u64,
+            MetricsField::LatestRtt => record.latest_rtt.as_nanos() as u64,
+            MetricsField::RttVariance => record.rtt_variance.as_nanos() as u64,
+            MetricsField::MaxAckDelay => record.max_ack_delay.as_nanos() as u64,
+            MetricsField::PtoCount => record.pto_count as u64,
+            MetricsField::CongestionWindow => record.congestion_window as u64,
+            MetricsField::BytesInFlight => record.bytes_in_flight as u64,
+        };
+    }
+}
+
+impl FromStr for MetricsField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return MetricsField::ALL
+            .into_iter()
+            .find(|field| field.name() == s)
+            .ok_or_else(|| {
+                let names = MetricsField::ALL.map(|field| field.name());
+                format!("unknown field '{}', expected one of {}", s, names.join(","))
+            });
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum RecordEncoding {
+    Csv,
+    /// Newline-delimited JSON, one object per record
+    Ndjson,
+    /// Tagged little-endian binary format, see `BinaryRecordWriter`
+    Binary,
+}
+
+/// Encoding and selected columns of the CC log
+#[derive(Clone, Debug)]
+pub struct RecordFormat {
+    pub encoding: RecordEncoding,
+    pub fields: Vec<MetricsField>,
+}
+
+impl RecordFormat {
+    /// Uses all fields if `fields` is empty
+    pub fn new(encoding: RecordEncoding, fields: Vec<MetricsField>) -> Self {
+        let fields = if fields.is_empty() {
+            MetricsField::ALL.to_vec()
+        } else {
+            fields
+        };
+
+        Self { encoding, fields }
+    }
+
+    pub fn record_writer(
+        &self,
+        writer: Box<dyn Write + Send>,
+    ) -> io::Result<Box<dyn RecordWriter>> {
+        let fields = self.fields.clone();
+
+        return Ok(match self.encoding {
+            RecordEncoding::Csv => Box::new(CsvRecordWriter { writer, fields }),
+            RecordEncoding::Ndjson => Box::new(NdjsonRecordWriter { writer, fields }),
+            RecordEncoding::Binary => Box::new(BinaryRecordWriter::new(writer, fields)?),
+        });
+    }
+}
+
+/// Encodes CC log records into an underlying writer
+pub trait RecordWriter: Send {
+    /// Writes a `key=value` metadata entry, only valid before `write_header`
+    fn write_metadata(&mut self, metadata: &str) -> io::Result<()>;
+
+    fn write_header(&mut self) -> io::Result<()>;
+
+    fn write_record(&mut self, record: &MetricsRecord) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+pub struct CsvRecordWriter {
+    writer: Box<dyn Write + Send>,
+    fields: Vec<MetricsField>,
+}
+
+impl RecordWriter for CsvRecordWriter {
+    fn write_metadata(&mut self, metadata: &str) -> io::Result<()> {
+        return writeln!(self.writer, "# {}", metadata);
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let names = self
+            .fields
+            .iter()
+            .map(|field| field.name())
+            .collect::<Vec<_>>();
+        return writeln!(self.writer, "{}", names.join(","));
+    }
+
+    fn write_record(&mut self, record: &MetricsRecord) -> io::Result<()> {
+        for (index, field) in self.fields.iter().enumerate() {
+            if index > 0 {
+                self.writer.write_all(b",")?;
+            }
+            write!(self.writer, "{}", field.value(record))?;
+        }
+
+        return writeln!(self.writer);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+pub struct NdjsonRecordWriter {
+    writer: Box<dyn Write + Send>,
+    fields: Vec<MetricsField>,
+}
+
+impl RecordWriter for NdjsonRecordWriter {
+    fn write_metadata(&mut self, metadata: &str) -> io::Result<()> {
+        let (key, value) = metadata.split_once('=').unwrap_or((metadata, ""));
+        let metadata = serde_json::json!({ "metadata": { key: value } });
+
+        return writeln!(self.writer, "{}", metadata);
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        // every record carries its field names
+        return Ok(());
+    }
+
+    fn write_record(&mut self, record: &MetricsRecord) -> io::Result<()> {
+        self.writer.write_all(b"{")?;
+        for (index, field) in self.fields.iter().enumerate() {
+            if index > 0 {
+                self.writer.write_all(b",")?;
+            }
+            write!(self.writer, "\"{}\":{}", field.name(), field.value(record))?;
+        }
+
+        return writeln!(self.writer, "}}");
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+/// Compact binary encoding. The file starts with the magic `CCLOG` and a version byte, followed
+/// by tagged entries:
+/// - `M`, u32 length, UTF-8 `key=value` metadata
+/// - `H`, u8 field count, per field a u8 length and the UTF-8 field name
+/// - `R`, one u64 per field in header order
+///
+/// All integers are little-endian.
+pub struct BinaryRecordWriter {
+    writer: Box<dyn Write + Send>,
+    fields: Vec<MetricsField>,
+}
+
+impl BinaryRecordWriter {
+    const MAGIC: &'static [u8] = b"CCLOG";
+    const VERSION: u8 = 1;
+
+    fn new(mut writer: Box<dyn Write + Send>, fields: Vec<MetricsField>) -> io::Result<Self> {
+        writer.write_all(Self::MAGIC)?;
+        writer.write_all(&[Self::VERSION])?;
+
+        return Ok(Self { writer, fields });
+    }
+}
+
+impl RecordWriter for BinaryRecordWriter {
+    fn write_metadata(&mut self, metadata: &str) -> io::Result<()> {
+        self.writer.write_all(b"M")?;
+        self.writer
+            .write_all(&(metadata.len() as u32).to_le_bytes())?;
+        return self.writer.write_all(metadata.as_bytes());
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.writer.write_all(b"H")?;
+        self.writer.write_all(&[self.fields.len() as u8])?;
+        for field in self.fields.iter() {
+            self.writer.write_all(&[field.name().len() as u8])?;
+            self.writer.write_all(field.name().as_bytes())?;
+        }
+
+        return Ok(());
+    }
+
+    fn write_record(&mut self, record: &MetricsRecord) -> io::Result<()> {
+        self.writer.write_all(b"R")?;
+        for field in self.fields.iter() {
+            self.writer.write_all(&field.value(record).to_le_bytes())?;
+        }
+
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Keeps the bytes written through a `RecordWriter`'s boxed writer readable by the test
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn bytes(&self) -> Vec<u8> {
+            return self.0.lock().unwrap().clone();
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.0.lock().unwrap().write(buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn record() -> MetricsRecord {
+        return MetricsRecord {
+            time: 1_000,
+            conn_id: 7,
+            min_rtt: Duration::from_micros(10),
+            smoothed_rtt: Duration::from_micros(12),
+            latest_rtt: Duration::from_micros(11),
+            rtt_variance: Duration::from_micros(2),
+            max_ack_delay: Duration::from_millis(25),
+            pto_count: 1,
+            congestion_window: 12_000,
+            bytes_in_flight: 3_000,
+        };
+    }
+
+    /// Writes a metadata entry, the header and one record
+    fn encode(encoding: RecordEncoding, fields: Vec<MetricsField>) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let mut writer = RecordFormat::new(encoding, fields)
+            .record_writer(Box::new(buffer.clone()))
+            .unwrap();
+        writer.write_metadata("alpn=perf").unwrap();
+        writer.write_header().unwrap();
+        writer.write_record(&record()).unwrap();
+        writer.flush().unwrap();
+
+        return buffer.bytes();
+    }
+
+    #[test]
+    fn parses_field_names() {
+        for field in MetricsField::ALL {
+            assert_eq!(field.name().parse::<MetricsField>(), Ok(field));
+        }
+        assert!("cwnd".parse::<MetricsField>().is_err());
+    }
+
+    #[test]
+    fn encodes_csv() {
+        let fields = vec![MetricsField::Time, MetricsField::CongestionWindow];
+        let csv = String::from_utf8(encode(RecordEncoding::Csv, fields)).unwrap();
+        assert_eq!(csv, "# alpn=perf\ntime,congestion_window\n1000,12000\n");
+    }
+
+    #[test]
+    fn encodes_all_fields_by_default() {
+        let csv = String::from_utf8(encode(RecordEncoding::Csv, Vec::new())).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[1..],
+            [
+                "time,conn_id,min_rtt,smoothed_rtt,latest_rtt,rtt_variance,max_ack_delay,pto_count,congestion_window,bytes_in_flight",
+                "1000,7,10000,12000,11000,2000,25000000,1,12000,3000",
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_ndjson() {
+        let fields = vec![MetricsField::ConnId, MetricsField::SmoothedRtt];
+        let ndjson = String::from_utf8(encode(RecordEncoding::Ndjson, fields)).unwrap();
+        let lines = ndjson
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                serde_json::json!({ "metadata": { "alpn": "perf" } }),
+                serde_json::json!({ "conn_id": 7, "smoothed_rtt": 12_000 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_binary() {
+        let fields = vec![MetricsField::PtoCount, MetricsField::BytesInFlight];
+        let mut expected = b"CCLOG\x01".to_vec();
+        expected.extend(b"M\x09\x00\x00\x00alpn=perf");
+        expected.extend(b"H\x02\x09pto_count\x0fbytes_in_flight");
+        expected.push(b'R');
+        expected.extend(1u64.to_le_bytes());
+        expected.extend(3_000u64.to_le_bytes());
+
+        assert_eq!(encode(RecordEncoding::Binary, fields), expected);
+    }
+
+    #[test]
+    fn keeps_event_detail_in_one_csv_column() {
+        let event = EventRecord {
+            time: 1_000,
+            conn_id: 7,
+            event: "congestion",
+            packet_number: None,
+            bytes: Some(1_200),
+            detail: "a=1,b=2".to_string(),
+        };
+
+        let mut csv = Vec::new();
+        event.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "1000,7,congestion,,1200,a=1;b=2\n"
+        );
+    }
+}
@@ -1,35 +1,354 @@
 use std::{
-    io::Write,
-    time::{SystemTime, UNIX_EPOCH},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use log::info;
-use s2n_quic::provider::event::{self, events::RecoveryMetrics, ConnectionMeta};
+use clap::ArgEnum;
+use flate2::{write::GzEncoder, Compression};
+
+use s2n_quic::provider::event::{
+    self,
+    events::{
+        ApplicationProtocolInformation, Congestion, ConnectionClosed, ConnectionStarted, Frame,
+        FrameReceived, PacketHeader, PacketLost, PacketSent, RecoveryMetrics,
+    },
+    ConnectionMeta,
+};
+
+use crate::{
+    metrics_writer::{AsyncMetricsWriter, LogOutput, LogSink},
+    record_writer::{EventRecord, MetricsRecord, RecordFormat},
+};
+
+/// Clock used for the `time` column of the CC and event logs, all values are in nanoseconds
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum TimestampMode {
+    /// Wall clock time since the Unix epoch, subject to clock adjustments
+    Unix,
+    /// The event's own s2n-quic timestamp, relative to the start of the endpoint
+    Event,
+    /// Monotonic time since the process started, shared by all loggers of the process
+    Monotonic,
+    /// The event's s2n-quic timestamp relative to the start of its connection
+    Connection,
+}
+
+/// Origin of `TimestampMode::Monotonic`, shared so the logs of all endpoints of a process line up
+static PROCESS_START: OnceLock<Instant> = OnceLock::new();
+
+/// Pins the origin of monotonic timestamps to now, called at the start of `main`
+pub fn init_process_start() {
+    PROCESS_START.get_or_init(Instant::now);
+}
+
+/// Placeholder in a logfile template that is replaced with the connection id
+pub const CONN_ID_PLACEHOLDER: &str = "{conn_id}";
+
+/// Compression of a logfile, selected by the extension of its path
+#[derive(Clone, Copy, Debug)]
+pub enum LogCompression {
+    Gzip,
+    Zstd,
+}
+
+/// Extensions of logfiles that are written compressed
+const COMPRESSION_EXTENSIONS: [(&str, LogCompression); 2] =
+    [("gz", LogCompression::Gzip), ("zst", LogCompression::Zstd)];
+
+/// Compression of the logfile at `path`, `None` if it is written uncompressed
+pub fn log_compression(path: &Path) -> Option<LogCompression> {
+    let extension = path.extension()?;
+    return COMPRESSION_EXTENSIONS
+        .iter()
+        .find(|(compression_extension, _)| extension == *compression_extension)
+        .map(|(_, compression)| *compression);
+}
+
+/// Creates a buffered logfile, compressed with gzip or zstd if the path ends in `.gz` or `.zst`.
+/// The compressed stream is finished when the writer is dropped.
+pub fn create_logfile(path: &str) -> io::Result<Box<dyn Write + Send>> {
+    let file = BufWriter::new(File::create(path)?);
+
+    return match log_compression(Path::new(path)) {
+        Some(LogCompression::Gzip) => Ok(Box::new(GzEncoder::new(file, Compression::default()))),
+        Some(LogCompression::Zstd) => Ok(Box::new(zstd::Encoder::new(file, 0)?.auto_finish())),
+        None => Ok(Box::new(file)),
+    };
+}
 
 pub struct RecoveryMetricsLogger {
-    logfile_writer: Box<dyn Write + Send>,
+    /// Writer thread shared by all logfiles of the process
+    writer: AsyncMetricsWriter,
+    /// Logfile shared by all connections, `None` if every connection writes its own logfile
+    logfile_writer: Option<LogSink>,
+    /// Per-connection logfile path containing `CONN_ID_PLACEHOLDER`
+    logfile_template: Option<String>,
+    /// Encoding and columns of the shared and per-connection logfiles
+    record_format: RecordFormat,
+    event_logfile_writer: Option<LogSink>,
+    timestamp_mode: TimestampMode,
+    /// Minimum time between two rows of a connection
+    sample_interval: Option<Duration>,
+    /// Minimum change of `congestion_window` or `bytes_in_flight` in bytes for a row to be written
+    change_threshold: Option<u32>,
+}
+
+pub struct IPAConnectionContext {
+    pto_count: u32,
+    /// s2n-quic timestamp of the connection start, relative to the start of the endpoint
+    start: Duration,
+    /// Per-connection logfile, `None` if the connection logs into the shared logfile
+    logfile_writer: Option<LogSink>,
+    /// Connection metadata written as `key=value` entries in front of the per-connection header
+    metadata: Vec<String>,
+    /// The per-connection header is written once the negotiated ALPN is part of the metadata
+    header_written: bool,
+    /// Rows of the per-connection logfile that arrived before the header was written
+    pending_records: Vec<MetricsRecord>,
+    /// s2n-quic timestamp, congestion window and bytes in flight of the last written row
+    last_written: Option<(Duration, u32, u32)>,
+    /// Latest row suppressed by sampling, written in front of the row following a loss or PTO
+    skipped_record: Option<MetricsRecord>,
+    /// Set by loss, congestion and PTO events so the next row bypasses sampling
+    force_write: bool,
 }
 
-pub struct IPAConnectionContext {}
+impl IPAConnectionContext {
+    /// Writes the metadata, the header and the rows held back so far into the per-connection
+    /// logfile if not done yet
+    fn write_header(&mut self) {
+        let logfile_writer = match &mut self.logfile_writer {
+            Some(logfile_writer) if !self.header_written => logfile_writer,
+            _ => return,
+        };
+
+        for metadata in self.metadata.iter() {
+            logfile_writer.push_metadata(metadata.clone());
+        }
+        logfile_writer.push_header();
+        for record in self.pending_records.drain(..) {
+            logfile_writer.push(record);
+        }
+
+        self.header_written = true;
+    }
+
+    /// Queues a row into the per-connection logfile, holding it back until the header is written,
+    /// or into the shared logfile
+    fn push_record(&mut self, shared_logfile_writer: Option<&LogSink>, record: MetricsRecord) {
+        match &self.logfile_writer {
+            Some(_) if !self.header_written => self.pending_records.push(record),
+            Some(logfile_writer) => logfile_writer.push(record),
+            None => {
+                if let Some(logfile_writer) = shared_logfile_writer {
+                    logfile_writer.push(record);
+                }
+            }
+        }
+    }
+
+    /// Whether the sampling interval elapsed or the change threshold was exceeded since the last
+    /// row, a row passing either filter is written
+    fn should_write(
+        &self,
+        now: Duration,
+        record: &MetricsRecord,
+        sample_interval: Option<Duration>,
+        change_threshold: Option<u32>,
+    ) -> bool {
+        let (last_time, last_congestion_window, last_bytes_in_flight) = match self.last_written {
+            Some(last_written) => last_written,
+            None => return true,
+        };
+
+        if sample_interval.is_none() && change_threshold.is_none() {
+            return true;
+        }
+
+        if let Some(sample_interval) = sample_interval {
+            if now.saturating_sub(last_time) >= sample_interval {
+                return true;
+            }
+        }
+
+        if let Some(change_threshold) = change_threshold {
+            let congestion_window_change =
+                record.congestion_window.abs_diff(last_congestion_window);
+            let bytes_in_flight_change = record.bytes_in_flight.abs_diff(last_bytes_in_flight);
+            if congestion_window_change > change_threshold
+                || bytes_in_flight_change > change_threshold
+            {
+                return true;
+            }
+        }
+
+        return false;
+    }
+
+    /// Returns the rows a `RecoveryMetrics` event writes: none while sampling suppresses it,
+    /// otherwise its row, preceded by the last suppressed row if a loss, congestion or PTO forced
+    /// the write
+    fn sample(
+        &mut self,
+        now: Duration,
+        record: MetricsRecord,
+        pto_expired: bool,
+        sample_interval: Option<Duration>,
+        change_threshold: Option<u32>,
+    ) -> Vec<MetricsRecord> {
+        let forced = self.force_write || pto_expired;
+        if !forced && !self.should_write(now, &record, sample_interval, change_threshold) {
+            self.skipped_record = Some(record);
+            return Vec::new();
+        }
+
+        let mut rows = Vec::with_capacity(2);
+        // keep the state right before a loss or PTO next to the state after it
+        if forced {
+            rows.extend(self.skipped_record.take());
+        }
+        self.skipped_record = None;
+        self.force_write = false;
+        self.last_written = Some((now, record.congestion_window, record.bytes_in_flight));
+        rows.push(record);
+        return rows;
+    }
+}
 
 impl RecoveryMetricsLogger {
-    pub fn new(mut logfile_writer: Box<dyn Write + Send>) -> Self {
-        let header_fields = [
-            "time",
-            "conn_id",
-            "min_rtt",
-            "smoothed_rtt",
-            "latest_rtt",
-            "rtt_variance",
-            "max_ack_delay",
-            "pto_count",
-            "congestion_window",
-            "bytes_in_flight",
-        ];
-
-        writeln!(logfile_writer, "{}", header_fields.join(",")).unwrap();
-
-        Self { logfile_writer }
+    pub fn new(
+        writer: AsyncMetricsWriter,
+        logfile_path: &str,
+        logfile_writer: Box<dyn Write + Send>,
+        record_format: RecordFormat,
+    ) -> Self {
+        let record_writer = record_format.record_writer(logfile_writer).unwrap();
+        let logfile_writer = writer.open(format!("CC logfile {}", logfile_path), move || {
+            return Ok(LogOutput::Metrics(record_writer));
+        });
+        logfile_writer.push_header();
+
+        Self {
+            writer,
+            logfile_writer: Some(logfile_writer),
+            logfile_template: None,
+            record_format,
+            event_logfile_writer: None,
+            timestamp_mode: TimestampMode::Unix,
+            sample_interval: None,
+            change_threshold: None,
+        }
+    }
+
+    /// Logs every connection into its own file, `logfile_template` is the path with
+    /// `CONN_ID_PLACEHOLDER` replaced by the connection id
+    pub fn per_connection(
+        writer: AsyncMetricsWriter,
+        logfile_template: String,
+        record_format: RecordFormat,
+    ) -> Self {
+        Self {
+            writer,
+            logfile_writer: None,
+            logfile_template: Some(logfile_template),
+            record_format,
+            event_logfile_writer: None,
+            timestamp_mode: TimestampMode::Unix,
+            sample_interval: None,
+            change_threshold: None,
+        }
+    }
+
+    /// Additionally logs packet sent/lost, ACK received, congestion and PTO events as typed rows
+    pub fn with_event_logfile(
+        mut self,
+        event_logfile_path: &str,
+        event_logfile_writer: Box<dyn Write + Send>,
+    ) -> Self {
+        let event_logfile_writer =
+            self.writer
+                .open(format!("event logfile {}", event_logfile_path), move || {
+                    return Ok(LogOutput::Events(event_logfile_writer));
+                });
+        event_logfile_writer.push_header();
+
+        self.event_logfile_writer = Some(event_logfile_writer);
+        self
+    }
+
+    pub fn with_timestamp_mode(mut self, timestamp_mode: TimestampMode) -> Self {
+        self.timestamp_mode = timestamp_mode;
+        self
+    }
+
+    /// Writes at most one row per `sample_interval` and connection
+    pub fn with_sample_interval(mut self, sample_interval: Duration) -> Self {
+        self.sample_interval = Some(sample_interval);
+        self
+    }
+
+    /// Only writes a row if `congestion_window` or `bytes_in_flight` changed by more than
+    /// `change_threshold` bytes since the last row of the connection
+    pub fn with_change_threshold(mut self, change_threshold: u32) -> Self {
+        self.change_threshold = Some(change_threshold);
+        self
+    }
+
+    /// Value of the `time` column for an event of the given connection
+    fn timestamp(&self, context: &IPAConnectionContext, meta: &ConnectionMeta) -> u128 {
+        return match self.timestamp_mode {
+            TimestampMode::Unix => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+            TimestampMode::Event => meta.timestamp.duration_since_start().as_nanos(),
+            TimestampMode::Monotonic => {
+                PROCESS_START.get_or_init(Instant::now).elapsed().as_nanos()
+            }
+            TimestampMode::Connection => meta
+                .timestamp
+                .duration_since_start()
+                .saturating_sub(context.start)
+                .as_nanos(),
+        };
+    }
+
+    /// Queues a row for the event log, `detail` is only built if an event log is written
+    fn log_event(
+        &self,
+        context: &IPAConnectionContext,
+        meta: &ConnectionMeta,
+        event: &'static str,
+        packet_number: Option<u64>,
+        bytes: Option<usize>,
+        detail: impl FnOnce() -> String,
+    ) {
+        let event_logfile_writer = match &self.event_logfile_writer {
+            Some(event_logfile_writer) => event_logfile_writer,
+            None => return,
+        };
+
+        event_logfile_writer.push_event(EventRecord {
+            time: self.timestamp(context, meta),
+            conn_id: meta.id,
+            event,
+            packet_number,
+            bytes,
+            detail: detail(),
+        });
+    }
+}
+
+pub fn packet_number(header: &PacketHeader) -> Option<u64> {
+    match header {
+        PacketHeader::Initial { number, .. }
+        | PacketHeader::Handshake { number, .. }
+        | PacketHeader::ZeroRtt { number, .. }
+        | PacketHeader::OneRtt { number, .. } => Some(*number),
+        _ => None,
     }
 }
 
@@ -38,42 +357,339 @@ impl event::Subscriber for RecoveryMetricsLogger {
 
     fn create_connection_context(
         &mut self,
-        _meta: &event::ConnectionMeta,
+        meta: &event::ConnectionMeta,
         _info: &event::ConnectionInfo,
     ) -> Self::ConnectionContext {
-        return IPAConnectionContext {};
+        let logfile_writer = self.logfile_template.as_ref().map(|template| {
+            let path = template.replace(CONN_ID_PLACEHOLDER, &meta.id.to_string());
+            let record_format = self.record_format.clone();
+            // the file is created on the writer thread, off the event path
+            self.writer.open(format!("CC logfile {}", path), move || {
+                let logfile = create_logfile(&path)?;
+                return Ok(LogOutput::Metrics(record_format.record_writer(logfile)?));
+            })
+        });
+
+        let start_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        return IPAConnectionContext {
+            pto_count: 0,
+            start: meta.timestamp.duration_since_start(),
+            logfile_writer,
+            metadata: vec![
+                format!("conn_id={}", meta.id),
+                format!("endpoint_type={:?}", meta.endpoint_type),
+                format!("start_time={}", start_time.as_nanos()),
+                format!("time_mode={:?}", self.timestamp_mode),
+            ],
+            header_written: false,
+            pending_records: Vec::new(),
+            last_written: None,
+            skipped_record: None,
+            force_write: false,
+        };
+    }
+
+    fn on_connection_started(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &ConnectionStarted,
+    ) {
+        context
+            .metadata
+            .push(format!("local_addr={:?}", event.path.local_addr));
+        context
+            .metadata
+            .push(format!("remote_addr={:?}", event.path.remote_addr));
+    }
+
+    fn on_application_protocol_information(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &ApplicationProtocolInformation,
+    ) {
+        context.metadata.push(format!(
+            "alpn={}",
+            String::from_utf8_lossy(event.chosen_application_protocol)
+        ));
+        context.write_header();
+    }
+
+    fn on_connection_closed(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        _event: &ConnectionClosed,
+    ) {
+        // the handshake failed before an ALPN was negotiated, write what is known
+        context.write_header();
     }
 
     fn on_recovery_metrics(
         &mut self,
-        _context: &mut Self::ConnectionContext,
+        context: &mut Self::ConnectionContext,
         meta: &ConnectionMeta,
         event: &RecoveryMetrics,
     ) {
-        let now = SystemTime::now();
-        let nanos_since_unix = now.duration_since(UNIX_EPOCH).unwrap();
-
-        writeln!(
-            self.logfile_writer,
-            "{},{},{},{},{},{},{},{},{},{}",
-            nanos_since_unix.as_nanos(),
-            meta.id,
-            event.min_rtt.as_nanos(),
-            event.smoothed_rtt.as_nanos(),
-            event.latest_rtt.as_nanos(),
-            event.rtt_variance.as_nanos(),
-            event.max_ack_delay.as_nanos(),
-            event.pto_count,
-            event.congestion_window,
-            event.bytes_in_flight
-        )
-        .unwrap();
+        let time = self.timestamp(context, meta);
+        let now = meta.timestamp.duration_since_start();
+        let record = MetricsRecord {
+            time,
+            conn_id: meta.id,
+            min_rtt: event.min_rtt,
+            smoothed_rtt: event.smoothed_rtt,
+            latest_rtt: event.latest_rtt,
+            rtt_variance: event.rtt_variance,
+            max_ack_delay: event.max_ack_delay,
+            pto_count: event.pto_count,
+            congestion_window: event.congestion_window,
+            bytes_in_flight: event.bytes_in_flight,
+        };
+
+        // s2n-quic has no dedicated PTO event, a PTO expiration shows up as an increased pto_count
+        let pto_expired = event.pto_count > context.pto_count;
+        context.pto_count = event.pto_count;
+
+        let rows = context.sample(
+            now,
+            record,
+            pto_expired,
+            self.sample_interval,
+            self.change_threshold,
+        );
+        for row in rows {
+            context.push_record(self.logfile_writer.as_ref(), row);
+        }
+
+        if pto_expired {
+            self.log_event(context, meta, "pto", None, None, || {
+                format!("pto_count={}", event.pto_count)
+            });
+        }
+    }
+
+    fn on_packet_sent(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &PacketSent,
+    ) {
+        self.log_event(
+            context,
+            meta,
+            "packet_sent",
+            packet_number(&event.packet_header),
+            None,
+            String::new,
+        );
+    }
+
+    fn on_packet_lost(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &PacketLost,
+    ) {
+        context.force_write = true;
+        self.log_event(
+            context,
+            meta,
+            "packet_lost",
+            packet_number(&event.packet_header),
+            Some(event.bytes_lost.into()),
+            || format!("is_mtu_probe={}", event.is_mtu_probe),
+        );
+    }
+
+    fn on_frame_received(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &FrameReceived,
+    ) {
+        if let Frame::Ack { .. } = event.frame {
+            self.log_event(
+                context,
+                meta,
+                "ack_received",
+                packet_number(&event.packet_header),
+                None,
+                || format!("{:?}", event.frame),
+            );
+        }
+    }
+
+    fn on_congestion(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        meta: &ConnectionMeta,
+        event: &Congestion,
+    ) {
+        context.force_write = true;
+        self.log_event(context, meta, "congestion", None, None, || {
+            format!("source={:?}", event.source)
+        });
     }
 }
 
-impl Drop for RecoveryMetricsLogger {
-    fn drop(&mut self) {
-        self.logfile_writer.flush().unwrap();
-        info!("Flushed logfile writer.");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> IPAConnectionContext {
+        return IPAConnectionContext {
+            pto_count: 0,
+            start: Duration::ZERO,
+            logfile_writer: None,
+            metadata: Vec::new(),
+            header_written: false,
+            pending_records: Vec::new(),
+            last_written: None,
+            skipped_record: None,
+            force_write: false,
+        };
+    }
+
+    fn record(time: u128, congestion_window: u32, bytes_in_flight: u32) -> MetricsRecord {
+        return MetricsRecord {
+            time,
+            conn_id: 0,
+            min_rtt: Duration::ZERO,
+            smoothed_rtt: Duration::ZERO,
+            latest_rtt: Duration::ZERO,
+            rtt_variance: Duration::ZERO,
+            max_ack_delay: Duration::ZERO,
+            pto_count: 0,
+            congestion_window,
+            bytes_in_flight,
+        };
+    }
+
+    fn ms(millis: u64) -> Duration {
+        return Duration::from_millis(millis);
+    }
+
+    /// Samples a row whose `time` is the event time in milliseconds, returns the times of the
+    /// written rows
+    fn sample(
+        context: &mut IPAConnectionContext,
+        millis: u64,
+        congestion_window: u32,
+        pto_expired: bool,
+        sample_interval: Option<Duration>,
+        change_threshold: Option<u32>,
+    ) -> Vec<u128> {
+        let row = record(millis as u128, congestion_window, 0);
+        return context
+            .sample(
+                ms(millis),
+                row,
+                pto_expired,
+                sample_interval,
+                change_threshold,
+            )
+            .iter()
+            .map(|row| row.time)
+            .collect();
+    }
+
+    #[test]
+    fn writes_every_row_without_filters() {
+        let mut context = context();
+        context.last_written = Some((ms(0), 10_000, 0));
+        assert!(context.should_write(ms(0), &record(0, 10_000, 0), None, None));
+    }
+
+    #[test]
+    fn writes_the_first_row() {
+        let context = context();
+        let row = record(0, 10_000, 0);
+        assert!(context.should_write(ms(0), &row, Some(ms(100)), Some(1_000)));
+    }
+
+    #[test]
+    fn samples_by_interval() {
+        let mut context = context();
+        context.last_written = Some((ms(100), 10_000, 0));
+        let interval = Some(ms(50));
+
+        assert!(!context.should_write(ms(149), &record(0, 20_000, 5_000), interval, None));
+        assert!(context.should_write(ms(150), &record(0, 10_000, 0), interval, None));
+    }
+
+    #[test]
+    fn samples_by_change_threshold() {
+        let mut context = context();
+        context.last_written = Some((ms(0), 10_000, 2_000));
+        let threshold = Some(1_000);
+
+        assert!(!context.should_write(ms(1_000), &record(0, 11_000, 1_000), None, threshold));
+        assert!(context.should_write(ms(0), &record(0, 8_999, 2_000), None, threshold));
+        assert!(context.should_write(ms(0), &record(0, 10_000, 3_001), None, threshold));
+    }
+
+    #[test]
+    fn writes_rows_passing_either_filter() {
+        let mut context = context();
+        context.last_written = Some((ms(100), 10_000, 0));
+        let (interval, threshold) = (Some(ms(50)), Some(1_000));
+
+        assert!(!context.should_write(ms(120), &record(0, 10_500, 0), interval, threshold));
+        assert!(context.should_write(ms(150), &record(0, 10_000, 0), interval, threshold));
+        assert!(context.should_write(ms(120), &record(0, 12_000, 0), interval, threshold));
+    }
+
+    #[test]
+    fn keeps_the_skipped_row_before_a_loss() {
+        let mut context = context();
+        let interval = Some(ms(100));
+
+        assert_eq!(sample(&mut context, 0, 10_000, false, interval, None), [0]);
+        assert!(sample(&mut context, 10, 11_000, false, interval, None).is_empty());
+        assert!(sample(&mut context, 20, 12_000, false, interval, None).is_empty());
+
+        context.force_write = true;
+        assert_eq!(
+            sample(&mut context, 30, 6_000, false, interval, None),
+            [20, 30]
+        );
+        assert!(!context.force_write);
+
+        // sampling applies again after the forced row
+        assert!(sample(&mut context, 40, 6_500, false, interval, None).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_skipped_row_before_a_pto() {
+        let mut context = context();
+        let threshold = Some(1_000);
+
+        assert_eq!(sample(&mut context, 0, 10_000, false, None, threshold), [0]);
+        assert!(sample(&mut context, 10, 10_500, false, None, threshold).is_empty());
+        assert_eq!(
+            sample(&mut context, 20, 10_500, true, None, threshold),
+            [10, 20]
+        );
+    }
+
+    #[test]
+    fn drops_the_skipped_row_on_a_sampled_write() {
+        let mut context = context();
+        let interval = Some(ms(100));
+
+        assert_eq!(sample(&mut context, 0, 10_000, false, interval, None), [0]);
+        assert!(sample(&mut context, 50, 11_000, false, interval, None).is_empty());
+        assert_eq!(
+            sample(&mut context, 100, 12_000, false, interval, None),
+            [100]
+        );
+
+        context.force_write = true;
+        assert_eq!(
+            sample(&mut context, 110, 6_000, false, interval, None),
+            [110]
+        );
     }
 }
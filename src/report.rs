@@ -0,0 +1,508 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::BufWriter,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::common::ReceiveStats;
+use clap::ArgEnum;
+use hdrhistogram::{
+    serialization::{interval_log::IntervalLogWriterBuilder, V2DeflateSerializer},
+    Histogram,
+};
+use log::info;
+use serde::Serialize;
+
+/// Percentiles of the latency table in the summary and the log
+const LATENCY_PERCENTILES: [f64; 8] = [50.0, 75.0, 90.0, 95.0, 99.0, 99.9, 99.99, 100.0];
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum SummaryFormat {
+    Json,
+    Csv,
+}
+
+/// Outcome of a single request/response exchange
+#[derive(Serialize, Debug, Clone)]
+pub struct RequestRecord {
+    /// Start of the request in nanoseconds since the Unix epoch, comparable to the CC log's `time`
+    pub start_time_ns: u64,
+    pub connection_id: u64,
+    pub stream_id: u64,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub send_duration_ns: u64,
+    /// Time from starting to read the response until the first response byte arrived, the read
+    /// starts together with the request in the `bidirectional` and `echo` modes
+    pub time_to_first_byte_ns: Option<u64>,
+    pub receive_duration_ns: u64,
+    /// Number of `receive_vectored` calls needed to read the response
+    pub receive_calls: u64,
+    pub send_throughput_bps: f64,
+    pub receive_throughput_bps: f64,
+    /// Response throughput between the first and the last response byte, `None` if the response
+    /// arrived at once
+    pub receive_goodput_bps: Option<f64>,
+    /// Time between the scheduled and the actual start of the request
+    pub queueing_delay_ns: u64,
+    /// Time from the scheduled start until the response completed, including queueing
+    pub latency_ns: u64,
+}
+
+impl RequestRecord {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        start_time: SystemTime,
+        connection_id: u64,
+        stream_id: u64,
+        request_bytes: u64,
+        send_duration: Duration,
+        receive_start_time: Instant,
+        receive_duration: Duration,
+        receive_stats: &ReceiveStats,
+        queueing_delay: Duration,
+        latency: Duration,
+    ) -> Self {
+        let response_bytes = receive_stats.received_bytes as u64;
+        let time_to_first_byte = receive_stats
+            .first_byte_time
+            .map(|first_byte_time| first_byte_time - receive_start_time);
+        let transfer_duration = receive_stats
+            .transfer_duration()
+            .filter(|transfer_duration| !transfer_duration.is_zero());
+
+        Self {
+            start_time_ns: start_time.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64,
+            connection_id,
+            stream_id,
+            request_bytes,
+            response_bytes,
+            send_duration_ns: send_duration.as_nanos() as u64,
+            time_to_first_byte_ns: time_to_first_byte.map(|ttfb| ttfb.as_nanos() as u64),
+            receive_duration_ns: receive_duration.as_nanos() as u64,
+            receive_calls: receive_stats.receive_calls as u64,
+            send_throughput_bps: throughput_bps(request_bytes, send_duration),
+            receive_throughput_bps: throughput_bps(response_bytes, receive_duration),
+            receive_goodput_bps: transfer_duration
+                .map(|transfer_duration| throughput_bps(response_bytes, transfer_duration)),
+            queueing_delay_ns: queueing_delay.as_nanos() as u64,
+            latency_ns: latency.as_nanos() as u64,
+        }
+    }
+}
+
+fn throughput_bps(bytes: u64, duration: Duration) -> f64 {
+    if duration.is_zero() {
+        return 0f64;
+    }
+
+    return bytes as f64 * 8f64 / duration.as_secs_f64();
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Statistics {
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Statistics {
+    fn from_values(mut values: Vec<f64>) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        Self {
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            median: percentile(&values, 50f64),
+            p95: percentile(&values, 95f64),
+            min: values[0],
+            max: values[values.len() - 1],
+        }
+    }
+}
+
+/// Picks one aggregate out of [`Statistics`], used for the aggregate rows of the CSV summary
+type Aggregate = fn(&Statistics) -> f64;
+
+/// Nearest-rank percentile of already sorted `values`
+fn percentile(values: &[f64], percentile: f64) -> f64 {
+    let rank = (percentile / 100f64 * values.len() as f64).ceil() as usize;
+    return values[rank.clamp(1, values.len()) - 1];
+}
+
+#[derive(Serialize, Debug)]
+pub struct PercentileRow {
+    pub percentile: f64,
+    pub latency_ns: u64,
+    /// Number of requests with at most this latency
+    pub count: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub request_count: usize,
+    pub total_request_bytes: u64,
+    pub total_response_bytes: u64,
+    pub send_duration_ns: Statistics,
+    pub time_to_first_byte_ns: Statistics,
+    pub receive_duration_ns: Statistics,
+    pub send_throughput_bps: Statistics,
+    pub receive_throughput_bps: Statistics,
+    pub receive_goodput_bps: Statistics,
+    pub queueing_delay_ns: Statistics,
+    pub latency_ns: Statistics,
+    /// Latency percentiles read from `latency_histogram`
+    pub latency_percentiles: Vec<PercentileRow>,
+    pub requests: Vec<RequestRecord>,
+    #[serde(skip)]
+    latency_histogram: Histogram<u64>,
+}
+
+impl Summary {
+    /// `significant_figures` is the precision of the latency histogram, between 0 and 5
+    pub fn new(
+        requests: Vec<RequestRecord>,
+        significant_figures: u8,
+    ) -> Result<Self, Box<dyn Error>> {
+        let collect = |f: fn(&RequestRecord) -> f64| requests.iter().map(f).collect::<Vec<_>>();
+
+        let mut latency_histogram = Histogram::<u64>::new(significant_figures)?;
+        for request in requests.iter() {
+            // the histogram resizes to fit, `saturating_record` would clamp to its initial range
+            latency_histogram.record(request.latency_ns)?;
+        }
+        let latency_percentiles = LATENCY_PERCENTILES
+            .into_iter()
+            .map(|percentile| {
+                let latency_ns = latency_histogram.value_at_percentile(percentile);
+                PercentileRow {
+                    percentile,
+                    latency_ns,
+                    count: latency_histogram.count_between(0, latency_ns),
+                }
+            })
+            .collect();
+
+        return Ok(Self {
+            request_count: requests.len(),
+            total_request_bytes: requests.iter().map(|r| r.request_bytes).sum(),
+            total_response_bytes: requests.iter().map(|r| r.response_bytes).sum(),
+            send_duration_ns: Statistics::from_values(collect(|r| r.send_duration_ns as f64)),
+            time_to_first_byte_ns: Statistics::from_values(
+                requests
+                    .iter()
+                    .filter_map(|r| r.time_to_first_byte_ns)
+                    .map(|ttfb| ttfb as f64)
+                    .collect(),
+            ),
+            receive_duration_ns: Statistics::from_values(collect(|r| r.receive_duration_ns as f64)),
+            send_throughput_bps: Statistics::from_values(collect(|r| r.send_throughput_bps)),
+            receive_throughput_bps: Statistics::from_values(collect(|r| r.receive_throughput_bps)),
+            receive_goodput_bps: Statistics::from_values(
+                requests
+                    .iter()
+                    .filter_map(|r| r.receive_goodput_bps)
+                    .collect(),
+            ),
+            queueing_delay_ns: Statistics::from_values(collect(|r| r.queueing_delay_ns as f64)),
+            latency_ns: Statistics::from_values(collect(|r| r.latency_ns as f64)),
+            latency_percentiles,
+            requests,
+            latency_histogram,
+        });
+    }
+
+    pub fn write_to_file(&self, path: &str, format: SummaryFormat) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        match format {
+            SummaryFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, self)?;
+                writeln!(writer)?;
+            }
+            SummaryFormat::Csv => self.write_csv(&mut writer)?,
+        }
+
+        writer.flush()?;
+
+        return Ok(());
+    }
+
+    /// Latency percentile table for the log, one line per percentile
+    pub fn latency_percentile_table(&self) -> String {
+        return self
+            .latency_percentiles
+            .iter()
+            .map(|row| {
+                format!(
+                    "{:>7}%: {:?} ({} requests)",
+                    row.percentile,
+                    Duration::from_nanos(row.latency_ns),
+                    row.count
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    /// Writes the latency histogram in the HdrHistogram interval log format (compressed V2
+    /// encoding), so histograms of several runs can be merged with the usual HdrHistogram tools.
+    ///
+    /// The log holds a single interval covering the whole run, from the first request start until
+    /// the last response completed.
+    pub fn write_latency_histogram(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_latency_histogram_log(&mut writer)?;
+        writer.flush()?;
+
+        return Ok(());
+    }
+
+    fn write_latency_histogram_log(&self, writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let mut serializer = V2DeflateSerializer::new();
+
+        let start_ns = self
+            .requests
+            .iter()
+            .map(|r| r.start_time_ns)
+            .min()
+            .unwrap_or_default();
+        let end_ns = self
+            .requests
+            .iter()
+            .map(|r| r.start_time_ns + r.latency_ns)
+            .max()
+            .unwrap_or_default();
+        let start_time = UNIX_EPOCH + Duration::from_nanos(start_ns);
+
+        let mut log_writer = IntervalLogWriterBuilder::new()
+            .add_comment("custom-perf request latencies in ns")
+            .with_start_time(start_time)
+            .with_base_time(start_time)
+            .begin_log_with(writer, &mut serializer)?;
+        log_writer
+            .write_histogram(
+                &self.latency_histogram,
+                Duration::ZERO,
+                Duration::from_nanos(end_ns.saturating_sub(start_ns)),
+                None,
+            )
+            .map_err(|e| format!("Failed to write latency histogram: {:?}", e))?;
+
+        return Ok(());
+    }
+
+    /// Writes one row per request followed by one row per aggregate. The `row_type` column is
+    /// `request` for request rows and the aggregate name (e.g. `p95`) for aggregate rows, which
+    /// leave the request and id columns empty.
+    fn write_csv(&self, writer: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let header_fields = [
+            "row_type",
+            "request",
+            "connection_id",
+            "stream_id",
+            "request_bytes",
+            "response_bytes",
+            "send_duration_ns",
+            "time_to_first_byte_ns",
+            "receive_duration_ns",
+            "send_throughput_bps",
+            "receive_throughput_bps",
+            "receive_goodput_bps",
+            "queueing_delay_ns",
+            "latency_ns",
+        ];
+
+        writeln!(writer, "{}", header_fields.join(","))?;
+
+        for (index, request) in self.requests.iter().enumerate() {
+            writeln!(
+                writer,
+                "request,{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                index,
+                request.connection_id,
+                request.stream_id,
+                request.request_bytes,
+                request.response_bytes,
+                request.send_duration_ns,
+                request
+                    .time_to_first_byte_ns
+                    .map(|ttfb| ttfb.to_string())
+                    .unwrap_or_default(),
+                request.receive_duration_ns,
+                request.send_throughput_bps,
+                request.receive_throughput_bps,
+                request
+                    .receive_goodput_bps
+                    .map(|goodput| goodput.to_string())
+                    .unwrap_or_default(),
+                request.queueing_delay_ns,
+                request.latency_ns
+            )?;
+        }
+
+        let aggregates: [(&str, Aggregate); 5] = [
+            ("mean", |s| s.mean),
+            ("median", |s| s.median),
+            ("p95", |s| s.p95),
+            ("min", |s| s.min),
+            ("max", |s| s.max),
+        ];
+
+        for (name, aggregate) in aggregates {
+            writeln!(
+                writer,
+                "{},,,,,,{},{},{},{},{},{},{},{}",
+                name,
+                aggregate(&self.send_duration_ns),
+                aggregate(&self.time_to_first_byte_ns),
+                aggregate(&self.receive_duration_ns),
+                aggregate(&self.send_throughput_bps),
+                aggregate(&self.receive_throughput_bps),
+                aggregate(&self.receive_goodput_bps),
+                aggregate(&self.queueing_delay_ns),
+                aggregate(&self.latency_ns)
+            )?;
+        }
+
+        return Ok(());
+    }
+}
+
+/// Writes a CSV row per completed request while the test is running
+pub struct RequestLogger {
+    logfile_writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl RequestLogger {
+    pub fn new(mut logfile_writer: Box<dyn Write + Send>) -> Self {
+        let header_fields = [
+            "time",
+            "conn_id",
+            "stream_id",
+            "request_bytes",
+            "response_bytes",
+            "send_duration",
+            "time_to_first_byte",
+            "receive_duration",
+            "queueing_delay",
+            "latency",
+        ];
+
+        writeln!(logfile_writer, "{}", header_fields.join(",")).unwrap();
+
+        Self {
+            logfile_writer: Mutex::new(logfile_writer),
+        }
+    }
+
+    pub fn log(&self, record: &RequestRecord) {
+        let mut logfile_writer = self.logfile_writer.lock().unwrap();
+
+        writeln!(
+            logfile_writer,
+            "{},{},{},{},{},{},{},{},{},{}",
+            record.start_time_ns,
+            record.connection_id,
+            record.stream_id,
+            record.request_bytes,
+            record.response_bytes,
+            record.send_duration_ns,
+            record
+                .time_to_first_byte_ns
+                .map(|ttfb| ttfb.to_string())
+                .unwrap_or_default(),
+            record.receive_duration_ns,
+            record.queueing_delay_ns,
+            record.latency_ns
+        )
+        .unwrap();
+    }
+}
+
+impl Drop for RequestLogger {
+    fn drop(&mut self) {
+        self.logfile_writer.get_mut().unwrap().flush().unwrap();
+        info!("Flushed request logfile writer.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use base64::Engine;
+    use hdrhistogram::serialization::{
+        interval_log::{IntervalLogIterator, LogEntry},
+        Deserializer,
+    };
+
+    use super::*;
+
+    fn request(start_time_ns: u64, latency_ns: u64) -> RequestRecord {
+        return RequestRecord {
+            start_time_ns,
+            connection_id: 0,
+            stream_id: 0,
+            request_bytes: 0,
+            response_bytes: 0,
+            send_duration_ns: 0,
+            time_to_first_byte_ns: None,
+            receive_duration_ns: 0,
+            receive_calls: 0,
+            send_throughput_bps: 0f64,
+            receive_throughput_bps: 0f64,
+            receive_goodput_bps: None,
+            queueing_delay_ns: 0,
+            latency_ns,
+        };
+    }
+
+    #[test]
+    fn rejects_invalid_latency_precision() {
+        assert!(Summary::new(vec![request(0, 1)], 6).is_err());
+    }
+
+    #[test]
+    fn latency_histogram_round_trips_through_interval_log() {
+        let start_ns = 1_600_000_000_000_000_000;
+        let requests = vec![
+            request(start_ns, 1_000_000),
+            request(start_ns + 1_000_000, 2_000_000),
+            request(start_ns + 2_000_000, 50_000_000),
+        ];
+        let summary = Summary::new(requests, 3).unwrap();
+
+        let mut log = Vec::new();
+        summary.write_latency_histogram_log(&mut log).unwrap();
+
+        let intervals = IntervalLogIterator::new(&log)
+            .map(Result::unwrap)
+            .filter_map(|entry| match entry {
+                LogEntry::Interval(interval) => Some(interval),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(intervals.len(), 1);
+        assert_eq!(intervals[0].start_timestamp(), Duration::ZERO);
+        assert_eq!(intervals[0].duration(), Duration::from_millis(52));
+
+        let encoded = base64::engine::general_purpose::STANDARD
+            .decode(intervals[0].encoded_histogram())
+            .unwrap();
+        let histogram: Histogram<u64> = Deserializer::new()
+            .deserialize(&mut Cursor::new(encoded))
+            .unwrap();
+        assert_eq!(histogram, summary.latency_histogram);
+        assert_eq!(histogram.len(), 3);
+        assert!(histogram.equivalent(histogram.max(), 50_000_000));
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    error::Error,
+    future::Future,
+    time::{Instant, SystemTime},
+};
+
+use futures_util::future::{BoxFuture, FutureExt};
+
+#[cfg(feature = "simulation")]
+use std::{panic::AssertUnwindSafe, time::UNIX_EPOCH};
+
+#[cfg(feature = "simulation")]
+use s2n_quic::provider::io::testing;
+
+#[cfg(feature = "simulation")]
+use crate::simulation::simulated_time;
+
+/// Executor and clock the request loops run on
+#[derive(Clone, Copy, Debug)]
+pub enum Runtime {
+    /// tokio in wall-clock time
+    Tokio,
+    /// The s2n-quic testing executor in simulated time, `origin` is the `Instant` the start of the
+    /// simulation maps to
+    #[cfg(feature = "simulation")]
+    Simulated { origin: Instant },
+}
+
+impl Runtime {
+    pub fn now(self) -> Instant {
+        return match self {
+            Runtime::Tokio => Instant::now(),
+            #[cfg(feature = "simulation")]
+            Runtime::Simulated { origin } => origin + simulated_time(),
+        };
+    }
+
+    /// Wall-clock time, or the simulated time since the start of the simulation counted from the
+    /// Unix epoch, like the CC log's `time` with `--cc-time event`
+    pub fn system_time(self) -> SystemTime {
+        return match self {
+            Runtime::Tokio => SystemTime::now(),
+            #[cfg(feature = "simulation")]
+            Runtime::Simulated { .. } => UNIX_EPOCH + simulated_time(),
+        };
+    }
+
+    pub async fn sleep_until(self, deadline: Instant) {
+        match self {
+            Runtime::Tokio => tokio::time::sleep_until(deadline.into()).await,
+            #[cfg(feature = "simulation")]
+            Runtime::Simulated { .. } => {
+                testing::time::delay(deadline.saturating_duration_since(self.now())).await
+            }
+        }
+    }
+
+    /// Spawns a task, the returned future resolves to its output or to an error if the task
+    /// panicked. The task keeps running if the future is dropped.
+    pub fn spawn<T: Send + 'static>(
+        self,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> BoxFuture<'static, Result<T, Box<dyn Error + Send + Sync>>> {
+        return match self {
+            Runtime::Tokio => {
+                let task = tokio::spawn(future);
+                async move { task.await.map_err(|e| e.into()) }.boxed()
+            }
+            #[cfg(feature = "simulation")]
+            Runtime::Simulated { .. } => {
+                // like tokio, contain the panic to the task instead of aborting the simulation
+                let task = testing::spawn(AssertUnwindSafe(future).catch_unwind());
+                async move { task.await.map_err(|_| "Task panicked".into()) }.boxed()
+            }
+        };
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    error::Error,
+    fs::create_dir_all,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    common::APPLICATION_PROTOCOL,
+    interval_reporter::IntervalReporter,
+    metrics_writer::AsyncMetricsWriter,
+    perf_server::handle_connection,
+    qlog::QlogLogger,
+    record_writer::{MetricsField, RecordEncoding, RecordFormat},
+    recovery_metrics_logger::{
+        create_logfile, init_process_start, RecoveryMetricsLogger, TimestampMode,
+        CONN_ID_PLACEHOLDER,
+    },
+};
+use clap::Parser;
+use log::info;
+use s2n_quic::Server;
+use tokio::{self, signal, sync::watch};
+
+mod common;
+mod interval_reporter;
+mod metrics_writer;
+mod perf_server;
+mod qlog;
+mod record_writer;
+mod recovery_metrics_logger;
+
+/// Perf server compatible with the perf client's request protocol
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct Args {
+    /// CC Logfile, `{conn_id}` in the path writes one file per connection
+    #[clap(short, long)]
+    cc_logfile: Option<String>,
+    /// Logfile for packet sent/lost, ACK, congestion and PTO events
+    #[clap(long, requires = "cc-logfile")]
+    cc_event_logfile: Option<String>,
+    /// Clock used for the `time` column of the CC logs
+    #[clap(long, arg_enum, default_value = "unix")]
+    cc_time: TimestampMode,
+    /// Comma-separated columns of the CC log, e.g. `time,min_rtt,congestion_window`, defaults to
+    /// all columns
+    #[clap(long, use_value_delimiter = true)]
+    cc_fields: Vec<MetricsField>,
+    /// Encoding of the CC log
+    #[clap(long, arg_enum, default_value = "csv")]
+    cc_format: RecordEncoding,
+    /// Write at most one CC log row per connection and interval (e.g. `10ms`)
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    cc_sample_interval: Option<Duration>,
+    /// Only write a CC log row if `congestion_window` or `bytes_in_flight` changed by more than
+    /// this many bytes
+    #[clap(long)]
+    cc_change_threshold: Option<u32>,
+    /// Directory to write a qlog (JSON-SEQ) file per connection into
+    #[clap(long)]
+    qlog: Option<String>,
+    #[clap(short, long, default_value = "4433")]
+    port: u16,
+    #[clap(long)]
+    cert_file: String,
+    #[clap(long)]
+    key_file: String,
+    #[clap(long)]
+    disable_gso: bool,
+    /// Report the throughput per stream and connection every this many seconds
+    #[clap(long)]
+    interval: Option<f64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    init_process_start();
+    env_logger::init();
+    let args = Args::parse();
+
+    if let Some(interval) = args.interval {
+        if !interval.is_finite() || interval <= 0f64 {
+            return Err("The report interval has to be a positive number of seconds!".into());
+        }
+    }
+
+    let tls = s2n_quic::provider::tls::s2n_tls::Server::builder()
+        .with_certificate(Path::new(&args.cert_file), Path::new(&args.key_file))?
+        .with_application_protocols(vec![APPLICATION_PROTOCOL])?
+        .build()?;
+
+    let mut io_builder = s2n_quic::provider::io::tokio::Provider::builder();
+
+    if args.disable_gso {
+        info!("Disabling GSO");
+        io_builder = io_builder.with_gso_disabled()?
+    }
+
+    let addr: SocketAddr = format!("0.0.0.0:{}", args.port).parse()?;
+    let io = io_builder.with_receive_address(addr)?.build()?;
+
+    let (log_writer, log_writer_thread) = AsyncMetricsWriter::spawn(false);
+
+    let record_format = RecordFormat::new(args.cc_format, args.cc_fields);
+    let metrics_logger = match args.cc_logfile {
+        Some(logfile_path) => {
+            let mut logger = if logfile_path.contains(CONN_ID_PLACEHOLDER) {
+                RecoveryMetricsLogger::per_connection(
+                    log_writer.clone(),
+                    logfile_path,
+                    record_format,
+                )
+            } else {
+                let logfile = create_logfile(&logfile_path)?;
+                RecoveryMetricsLogger::new(
+                    log_writer.clone(),
+                    &logfile_path,
+                    logfile,
+                    record_format,
+                )
+            };
+            if let Some(cc_event_logfile) = args.cc_event_logfile {
+                let logfile = create_logfile(&cc_event_logfile)?;
+                logger = logger.with_event_logfile(&cc_event_logfile, logfile);
+            }
+            if let Some(cc_sample_interval) = args.cc_sample_interval {
+                logger = logger.with_sample_interval(cc_sample_interval);
+            }
+            if let Some(cc_change_threshold) = args.cc_change_threshold {
+                logger = logger.with_change_threshold(cc_change_threshold);
+            }
+            Some(logger.with_timestamp_mode(args.cc_time))
+        }
+        None => None,
+    };
+
+    let qlog_logger = match args.qlog {
+        Some(qlog_dir) => {
+            create_dir_all(&qlog_dir)?;
+            Some(QlogLogger::new(PathBuf::from(qlog_dir), log_writer))
+        }
+        None => None,
+    };
+
+    let builder = Server::builder().with_tls(tls)?.with_io(io)?;
+
+    let mut server = match (metrics_logger, qlog_logger) {
+        (Some(metrics_logger), Some(qlog_logger)) => {
+            builder.with_event((metrics_logger, qlog_logger))?.start()?
+        }
+        (Some(metrics_logger), None) => builder.with_event(metrics_logger)?.start()?,
+        (None, Some(qlog_logger)) => builder.with_event(qlog_logger)?.start()?,
+        (None, None) => builder.start()?,
+    };
+
+    let (exit_sender, mut quitting_receiver) = watch::channel::<bool>(false);
+
+    tokio::spawn(async move {
+        let _ = signal::ctrl_c().await;
+        info!("Received ctrl-c, sending exit.");
+        let _ = exit_sender.send(true);
+    });
+
+    let interval_reporter = args.interval.map(|interval| {
+        let interval_reporter = Arc::new(IntervalReporter::default());
+        let reporter = interval_reporter.clone();
+        let quitting_receiver = quitting_receiver.clone();
+        tokio::spawn(async move {
+            reporter
+                .run(Duration::from_secs_f64(interval), quitting_receiver)
+                .await
+        });
+        interval_reporter
+    });
+
+    info!("Perf-Server listening on {}.", addr);
+
+    loop {
+        tokio::select! {
+            accepted = server.accept() => {
+                match accepted {
+                    Some(connection) => {
+                        tokio::spawn(handle_connection(
+                            connection,
+                            interval_reporter.clone(),
+                            quitting_receiver.clone(),
+                            |stream_task| {
+                                tokio::spawn(stream_task);
+                            },
+                        ));
+                    }
+                    None => {
+                        info!("Server endpoint closed, quitting.");
+                        break;
+                    }
+                }
+            },
+            _ = quitting_receiver.changed() => {
+                if *quitting_receiver.borrow() {
+                    info!("Received SIGINT, quitting.");
+                    break;
+                }
+            }
+        }
+    }
+
+    log_writer_thread.shutdown();
+
+    return Ok(());
+}
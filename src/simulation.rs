@@ -0,0 +1,214 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    error::Error,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use log::info;
+use s2n_quic::{
+    provider::{
+        io::testing::{
+            self,
+            network::{Buffers, Packet},
+            Handle, Network,
+        },
+        random,
+    },
+    Server,
+};
+use s2n_quic_core::{
+    crypto::tls::testing::certificates,
+    event::{IntoEvent, Timestamp},
+    inet::SocketAddress,
+};
+use tokio::sync::watch;
+
+use crate::{common::APPLICATION_PROTOCOL, perf_server::handle_connection};
+
+/// Server name of the s2n-quic test certificate presented by the simulated server
+pub const SERVER_NAME: &str = "localhost";
+
+/// Simulated network of `--simulate`, applied to the packets in both directions
+#[derive(clap::Args, Debug, Clone)]
+pub struct SimulationConfig {
+    /// Run the client against a perf server in this process over a simulated network in
+    /// simulated time instead of connecting to `--remote`, runs with the same options produce the
+    /// same CC logs
+    #[clap(long, conflicts_with_all = &["remote", "cert-file", "disable-gso", "interval"])]
+    pub simulate: bool,
+    /// One-way delay of the simulated network
+    #[clap(long, requires = "simulate", default_value = "50ms", parse(try_from_str = humantime::parse_duration))]
+    pub sim_delay: Duration,
+    /// Random gap of up to this duration between the packets of a transmission round
+    #[clap(long, requires = "simulate", parse(try_from_str = humantime::parse_duration))]
+    pub sim_jitter: Option<Duration>,
+    /// Random extra delay of up to this duration per packet, which reorders packets
+    #[clap(long, requires = "simulate", parse(try_from_str = humantime::parse_duration))]
+    pub sim_network_jitter: Option<Duration>,
+    /// Random loss in percent
+    #[clap(long, requires = "simulate")]
+    pub sim_loss: Option<f64>,
+    /// Maximum number of packets the simulated network transmits per round, all by default
+    #[clap(long, requires = "simulate")]
+    pub sim_transmit_rate: Option<u64>,
+    /// Seed of the simulated network and of the task scheduling
+    #[clap(long, requires = "simulate", default_value = "0")]
+    pub sim_seed: u64,
+}
+
+impl SimulationConfig {
+    /// Network with the configured properties
+    pub fn network(&self) -> SimulatedNetwork {
+        return SimulatedNetwork {
+            config: self.clone(),
+            backlog: VecDeque::new(),
+        };
+    }
+}
+
+/// Network of the simulation, delays, jitters, drops and rate limits packets like the s2n-quic
+/// testing `Model`.
+///
+/// `Model` takes the packets of the endpoints in the iteration order of a `HashMap`, which differs
+/// between runs as soon as several endpoints transmit in the same round. The packets are ordered
+/// by the address of their endpoint here, so a run only depends on its options and the seed.
+pub struct SimulatedNetwork {
+    config: SimulationConfig,
+    /// Packets taken from the endpoints that exceeded the transmit rate
+    backlog: VecDeque<Packet>,
+}
+
+impl Network for SimulatedNetwork {
+    fn execute(&mut self, buffers: &Buffers) -> usize {
+        let transmit_rate = self.config.sim_transmit_rate.unwrap_or(u64::MAX) as usize;
+
+        // while the backlog covers the next rounds, packets stay in the queues of the endpoints
+        if self.backlog.len() < transmit_rate {
+            let mut queues = BTreeMap::<SocketAddress, VecDeque<Packet>>::new();
+            buffers.pending_transmissions(|packet| {
+                queues
+                    .entry(*packet.path.local_address)
+                    .or_default()
+                    .push_back(packet);
+                return Ok(());
+            });
+
+            // the endpoints take turns, starting in random order like in `Model`
+            let mut queues: Vec<_> = queues.into_values().collect();
+            testing::rand::shuffle(&mut queues);
+            while !queues.is_empty() {
+                for queue in &mut queues {
+                    self.backlog.extend(queue.pop_front());
+                }
+                queues.retain(|queue| !queue.is_empty());
+            }
+        }
+
+        let loss = self.config.sim_loss.unwrap_or(0f64) / 100f64;
+        let mut transmit_time = testing::now() + self.config.sim_delay;
+        let count = self.backlog.len().min(transmit_rate);
+
+        for mut packet in self.backlog.drain(..count) {
+            if loss > 0f64 && testing::rand::gen::<f64>() < loss {
+                continue;
+            }
+
+            transmit_time += gen_jitter(self.config.sim_jitter);
+            let receive_time = transmit_time + gen_jitter(self.config.sim_network_jitter);
+
+            // the receiver sees the addresses the other way round
+            packet.switch();
+
+            let buffers = buffers.clone();
+            testing::spawn(async move {
+                testing::time::delay_until(receive_time).await;
+                buffers.rx(*packet.path.local_address, |queue| queue.receive(packet));
+            });
+        }
+
+        return count;
+    }
+}
+
+/// Random duration of up to `max_jitter`
+fn gen_jitter(max_jitter: Option<Duration>) -> Duration {
+    return match max_jitter {
+        Some(max_jitter) if !max_jitter.is_zero() => {
+            Duration::from_micros(testing::rand::gen_range(0..max_jitter.as_micros() as u64))
+        }
+        _ => Duration::ZERO,
+    };
+}
+
+/// Random numbers of the simulated endpoints, drawn from the seeded generator of the simulation.
+///
+/// The default generator is seeded by the OS and also decides e.g. when packet numbers are
+/// skipped, which would make every run different. Connection ids and tokens become predictable,
+/// which doesn't matter on a simulated network.
+#[derive(Debug, Default)]
+pub struct SimulatedRandom;
+
+impl random::Provider for SimulatedRandom {
+    type Generator = Self;
+    type Error = Infallible;
+
+    fn start(self) -> Result<Self::Generator, Self::Error> {
+        return Ok(self);
+    }
+}
+
+impl random::Generator for SimulatedRandom {
+    fn public_random_fill(&mut self, dest: &mut [u8]) {
+        testing::rand::fill_bytes(dest);
+    }
+
+    fn private_random_fill(&mut self, dest: &mut [u8]) {
+        testing::rand::fill_bytes(dest);
+    }
+}
+
+/// Simulated time since the start of the simulation, the clock of the events of simulated
+/// endpoints
+pub fn simulated_time() -> Duration {
+    let now: Timestamp = testing::now().into_event();
+    return now.duration_since_start();
+}
+
+/// Starts a perf server with the s2n-quic test certificate on the simulated network, returns its
+/// address
+pub fn start_server(
+    handle: &Handle,
+    quitting_receiver: watch::Receiver<bool>,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    let tls = s2n_quic::provider::tls::s2n_tls::Server::builder()
+        .with_certificate(certificates::CERT_PEM, certificates::KEY_PEM)?
+        .with_application_protocols(vec![APPLICATION_PROTOCOL])?
+        .build()?;
+
+    let mut server = Server::builder()
+        .with_tls(tls)?
+        .with_io(handle.builder().build()?)?
+        .with_random(SimulatedRandom)?
+        .start()?;
+    let addr = server.local_addr()?;
+
+    info!("Simulated Perf-Server listening on {}.", addr);
+
+    // not a primary task, the simulation ends once the client is done
+    testing::spawn(async move {
+        while let Some(connection) = server.accept().await {
+            testing::spawn(handle_connection(
+                connection,
+                None,
+                quitting_receiver.clone(),
+                |stream_task| {
+                    testing::spawn(stream_task);
+                },
+            ));
+        }
+    });
+
+    return Ok(addr);
+}
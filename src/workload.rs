@@ -0,0 +1,328 @@
+use std::{error::Error, f64::consts::PI, fmt, fs, time::Duration};
+
+use bytesize::ByteSize;
+use clap::ArgEnum;
+use rand::{rngs::StdRng, Rng};
+
+/// Distribution of request or response sizes.
+///
+/// Parsed from one of
+/// - `<size>`: fixed size, e.g. `1MiB`
+/// - `uniform:<min>-<max>`: uniformly distributed between `min` and `max` (inclusive)
+/// - `exp:<mean>`: exponentially distributed with the given mean
+/// - `lognormal:<median>,<sigma>`: log-normally distributed, `sigma` is the standard deviation of
+///   the underlying normal distribution
+/// - `empirical:<path>`: uniformly drawn from the sizes in the file, one size per line
+#[derive(Debug, Clone)]
+pub enum SizeDistribution {
+    Fixed(u64),
+    Uniform { min: u64, max: u64 },
+    Exponential { mean: f64 },
+    LogNormal { median: f64, sigma: f64 },
+    Empirical { path: String, sizes: Vec<u64> },
+}
+
+fn parse_size(value: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    return Ok(value.trim().parse::<ByteSize>()?.as_u64());
+}
+
+impl SizeDistribution {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let (kind, parameters) = match value.split_once(':') {
+            Some(split) => split,
+            None => return Ok(SizeDistribution::Fixed(parse_size(value)?)),
+        };
+
+        let distribution = match kind {
+            "uniform" => {
+                let (min, max) = parameters
+                    .split_once('-')
+                    .ok_or("expected uniform:<min>-<max>")?;
+                let (min, max) = (parse_size(min)?, parse_size(max)?);
+                if min > max {
+                    return Err("uniform minimum is greater than its maximum".into());
+                }
+                SizeDistribution::Uniform { min, max }
+            }
+            "exp" => SizeDistribution::Exponential {
+                mean: parse_size(parameters)? as f64,
+            },
+            "lognormal" => {
+                let (median, sigma) = parameters
+                    .split_once(',')
+                    .ok_or("expected lognormal:<median>,<sigma>")?;
+                let sigma: f64 = sigma.trim().parse()?;
+                if !sigma.is_finite() || sigma < 0f64 {
+                    return Err("lognormal sigma has to be a non-negative number".into());
+                }
+                SizeDistribution::LogNormal {
+                    median: parse_size(median)? as f64,
+                    sigma,
+                }
+            }
+            "empirical" => {
+                let sizes = fs::read_to_string(parameters)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(parse_size)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if sizes.is_empty() {
+                    return Err(format!("no sizes in {}", parameters).into());
+                }
+                SizeDistribution::Empirical {
+                    path: parameters.to_string(),
+                    sizes,
+                }
+            }
+            _ => return Err(format!("unknown size distribution '{}'", kind).into()),
+        };
+
+        return Ok(distribution);
+    }
+
+    /// Largest size the distribution can produce, `None` if it is unbounded
+    pub fn max(&self) -> Option<u64> {
+        return match self {
+            SizeDistribution::Fixed(size) => Some(*size),
+            SizeDistribution::Uniform { max, .. } => Some(*max),
+            SizeDistribution::Exponential { .. } | SizeDistribution::LogNormal { .. } => None,
+            SizeDistribution::Empirical { sizes, .. } => sizes.iter().copied().max(),
+        };
+    }
+
+    pub fn sample(&self, rng: &mut StdRng) -> u64 {
+        return match self {
+            SizeDistribution::Fixed(size) => *size,
+            SizeDistribution::Uniform { min, max } => rng.gen_range(*min..=*max),
+            SizeDistribution::Exponential { mean } => sample_exponential(rng, *mean) as u64,
+            SizeDistribution::LogNormal { median, sigma } => {
+                (median.ln() + sigma * sample_standard_normal(rng)).exp() as u64
+            }
+            SizeDistribution::Empirical { sizes, .. } => sizes[rng.gen_range(0..sizes.len())],
+        };
+    }
+}
+
+impl fmt::Display for SizeDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = |bytes: f64| ByteSize(bytes as u64).to_string_as(true);
+
+        return match self {
+            SizeDistribution::Fixed(bytes) => write!(f, "{}", size(*bytes as f64)),
+            SizeDistribution::Uniform { min, max } => {
+                write!(f, "uniform {}-{}", size(*min as f64), size(*max as f64))
+            }
+            SizeDistribution::Exponential { mean } => write!(f, "exp mean {}", size(*mean)),
+            SizeDistribution::LogNormal { median, sigma } => {
+                write!(f, "lognormal median {} sigma {}", size(*median), sigma)
+            }
+            SizeDistribution::Empirical { path, sizes } => {
+                write!(f, "empirical {} ({} sizes)", path, sizes.len())
+            }
+        };
+    }
+}
+
+/// Pause between the end of a request and the start of the next one on the same stream slot.
+///
+/// Parsed from `<duration>` for a fixed pause or `exp:<duration>` for exponentially distributed
+/// pauses with the given mean.
+#[derive(Debug, Clone, Copy)]
+pub enum ThinkTime {
+    Fixed(Duration),
+    Exponential(Duration),
+}
+
+impl ThinkTime {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        return Ok(match value.strip_prefix("exp:") {
+            Some(mean) => ThinkTime::Exponential(humantime::parse_duration(mean)?),
+            None => ThinkTime::Fixed(humantime::parse_duration(value)?),
+        });
+    }
+
+    pub fn sample(&self, rng: &mut StdRng) -> Duration {
+        return match self {
+            ThinkTime::Fixed(duration) => *duration,
+            ThinkTime::Exponential(mean) => {
+                Duration::from_secs_f64(sample_exponential(rng, mean.as_secs_f64()))
+            }
+        };
+    }
+}
+
+/// Spacing of open-loop request starts
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum ArrivalProcess {
+    /// Requests start at a fixed interval
+    Fixed,
+    /// Exponentially distributed intervals, i.e. a Poisson process
+    Poisson,
+}
+
+impl ArrivalProcess {
+    /// Time between two request starts at `rate` requests per second
+    pub fn interval(&self, rate: f64, rng: &mut StdRng) -> Duration {
+        return match self {
+            ArrivalProcess::Fixed => Duration::from_secs_f64(1f64 / rate),
+            ArrivalProcess::Poisson => {
+                Duration::from_secs_f64(sample_exponential(rng, 1f64 / rate))
+            }
+        };
+    }
+}
+
+fn sample_exponential(rng: &mut StdRng, mean: f64) -> f64 {
+    // 1 - u is in (0, 1], so the logarithm is finite
+    let u: f64 = rng.gen();
+    return -mean * (1f64 - u).ln();
+}
+
+/// Box-Muller transform
+fn sample_standard_normal(rng: &mut StdRng) -> f64 {
+    let u1: f64 = 1f64 - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    return (-2f64 * u1.ln()).sqrt() * (2f64 * PI * u2).cos();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn sample_mean(distribution: &SizeDistribution, samples: usize) -> f64 {
+        let mut rng = StdRng::seed_from_u64(42);
+        let sum: f64 = (0..samples)
+            .map(|_| distribution.sample(&mut rng) as f64)
+            .sum();
+        return sum / samples as f64;
+    }
+
+    #[test]
+    fn parses_sizes_and_distributions() {
+        assert!(matches!(
+            SizeDistribution::parse("1KiB").unwrap(),
+            SizeDistribution::Fixed(1024)
+        ));
+        assert!(matches!(
+            SizeDistribution::parse("uniform:1KiB-2KiB").unwrap(),
+            SizeDistribution::Uniform {
+                min: 1024,
+                max: 2048
+            }
+        ));
+        assert!(matches!(
+            SizeDistribution::parse("exp:1KiB").unwrap(),
+            SizeDistribution::Exponential { mean } if mean == 1024f64
+        ));
+        assert!(matches!(
+            SizeDistribution::parse("lognormal:1KiB,0.5").unwrap(),
+            SizeDistribution::LogNormal { median, sigma } if median == 1024f64 && sigma == 0.5
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_distributions() {
+        for value in [
+            "",
+            "abc",
+            "uniform:1KiB",
+            "uniform:a-b",
+            "exp:",
+            "exp:abc",
+            "lognormal:1KiB",
+            "lognormal:1KiB,abc",
+            "pareto:1KiB",
+            "empirical:/nonexistent/sizes.txt",
+        ] {
+            assert!(
+                SizeDistribution::parse(value).is_err(),
+                "accepted {:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_uniform_min_greater_than_max() {
+        assert!(SizeDistribution::parse("uniform:2KiB-1KiB").is_err());
+        assert!(SizeDistribution::parse("uniform:1KiB-1KiB").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_lognormal_sigma() {
+        for sigma in ["-1", "NaN", "inf"] {
+            let value = format!("lognormal:1KiB,{}", sigma);
+            assert!(
+                SizeDistribution::parse(&value).is_err(),
+                "accepted {:?}",
+                value
+            );
+        }
+        assert!(SizeDistribution::parse("lognormal:1KiB,0").is_ok());
+    }
+
+    #[test]
+    fn parses_empirical_sizes() {
+        let path = env::temp_dir().join(format!("custom-perf-sizes-{}.txt", std::process::id()));
+        fs::write(&path, "# sizes\n1KiB\n\n2KiB\n").unwrap();
+        let distribution = SizeDistribution::parse(&format!("empirical:{}", path.display()));
+
+        fs::write(&path, "# no sizes\n").unwrap();
+        let empty = SizeDistribution::parse(&format!("empirical:{}", path.display()));
+        fs::remove_file(&path).unwrap();
+
+        let distribution = distribution.unwrap();
+        assert_eq!(distribution.max(), Some(2048));
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            assert!([1024, 2048].contains(&distribution.sample(&mut rng)));
+        }
+        assert!(empty.is_err());
+    }
+
+    #[test]
+    fn seeded_samples_have_the_configured_mean() {
+        let uniform = SizeDistribution::parse("uniform:1000-3000").unwrap();
+        assert!((sample_mean(&uniform, 100_000) - 2000f64).abs() < 20f64);
+
+        let exponential = SizeDistribution::parse("exp:10000").unwrap();
+        assert!((sample_mean(&exponential, 100_000) - 10000f64).abs() < 200f64);
+
+        // the mean of a log-normal distribution is median * exp(sigma^2 / 2)
+        let lognormal = SizeDistribution::parse("lognormal:10000,0.5").unwrap();
+        let expected = 10000f64 * (0.5f64 * 0.5 / 2f64).exp();
+        assert!((sample_mean(&lognormal, 100_000) - expected).abs() < expected * 0.02);
+    }
+
+    #[test]
+    fn seeded_samples_are_reproducible() {
+        let distribution = SizeDistribution::parse("exp:1KiB").unwrap();
+        let draw = || {
+            let mut rng = StdRng::seed_from_u64(7);
+            (0..10)
+                .map(|_| distribution.sample(&mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(draw(), draw());
+    }
+
+    #[test]
+    fn parses_think_times() {
+        assert!(matches!(
+            ThinkTime::parse("10ms").unwrap(),
+            ThinkTime::Fixed(duration) if duration == Duration::from_millis(10)
+        ));
+        assert!(matches!(
+            ThinkTime::parse("exp:10ms").unwrap(),
+            ThinkTime::Exponential(duration) if duration == Duration::from_millis(10)
+        ));
+        assert!(ThinkTime::parse("exp:abc").is_err());
+        assert!(ThinkTime::parse("").is_err());
+    }
+}